@@ -21,6 +21,8 @@ export default function () {
 
   const res = ws.connect(WS_URL, {}, function (socket) {
     socket.on("open", function () {
+      // ask for JSON so server timestamps can be read
+      socket.send("/format json");
       // sanity check
       // send an initial ping
       socket.send("hello-from-k6");
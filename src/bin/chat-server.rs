@@ -1,336 +1,5106 @@
-use axum::extract::{
-    State,
-    ws::{Message, WebSocket, WebSocketUpgrade},
-};
-use axum::response::IntoResponse;
-use axum::{Router, routing};
-use bytes::Bytes;
-use chrono::{TimeZone, Utc};
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::SystemTime;
-use tokio::net::TcpListener;
-use tokio::sync::broadcast::{self, Sender};
-use tokio::time;
-
-use rust_final_project::random_name;
-
-macro_rules! b {
-    ($result:expr) => {
-        match $result {
-            Ok(ok) => ok,
-            Err(err) => break Err(err.into()),
-        }
-    };
-}
-
-const HELP_MSG: &str = include_str!("help.txt");
-const MAIN: &str = "main";
-
-#[derive(Clone, Debug, serde::Serialize)]
-struct ChatMessage {
-    message: String,
-    timestamp: i64,
-}
-
-impl ChatMessage {
-    fn new(message: String) -> Self {
-        ChatMessage {
-            message,
-            timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64,
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
-struct Names {
-    existing: Arc<Mutex<HashSet<String>>>,
-}
-
-impl Names {
-    fn new() -> Self {
-        return Names {
-            existing: Arc::new(Mutex::new(HashSet::new())),
-        };
-    }
-    fn insert(&self, str: String) -> bool {
-        return self.existing.lock().unwrap().insert(str);
-    }
-    fn remove(&self, str: &String) -> bool {
-        return self.existing.lock().unwrap().remove(str);
-    }
-    fn get_unique(&self) -> String {
-        let mut new_str = random_name();
-        while !self.insert(new_str.clone()) {
-            new_str = random_name();
-        }
-        return new_str;
-    }
-    fn get_existing(&self) -> Vec<String> {
-        let mut names = Vec::new();
-        for s in self.existing.lock().unwrap().iter() {
-            names.push(s.clone());
-        }
-        return names;
-    }
-}
-
-struct Room {
-    tx: Sender<ChatMessage>,
-    users: HashSet<String>,
-}
-
-impl Room {
-    fn new() -> Self {
-        let (tx, _) = broadcast::channel(32);
-        let users = HashSet::new();
-        return Self {
-            tx: tx,
-            users: users,
-        };
-    }
-}
-
-#[derive(Clone)]
-struct Rooms(Arc<RwLock<HashMap<String, Room>>>);
-impl Rooms {
-    fn new() -> Self {
-        return Self(Arc::new(RwLock::new(HashMap::new())));
-    }
-    fn join(&self, room_name: &str, user_name: &str) -> Sender<ChatMessage> {
-        let mut write_guard = self.0.write().unwrap();
-        let room = write_guard
-            .entry(room_name.to_owned())
-            .or_insert(Room::new());
-        room.users.insert(user_name.to_owned());
-        return room.tx.clone();
-    }
-    fn leave(&self, room_name: &str, user_name: &str) {
-        let mut write_guard = self.0.write().unwrap();
-        let mut delete_room = false;
-        if let Some(room) = write_guard.get_mut(room_name) {
-            room.users.remove(user_name);
-            delete_room = room.tx.receiver_count() <= 1;
-        }
-        if delete_room {
-            write_guard.remove(room_name);
-        }
-    }
-    fn change(&self, prev_room: &str, next_room: &str, user_name: &str) -> Sender<ChatMessage> {
-        self.leave(prev_room, user_name);
-        return self.join(next_room, user_name);
-    }
-    fn change_name(&self, room_name: &str, old_name: &str, new_name: &str) -> anyhow::Result<()> {
-        let mut write_guard = self.0.write().unwrap();
-        if let Some(room) = write_guard.get_mut(room_name) {
-            room.users.remove(old_name);
-            room.users.insert(new_name.to_owned());
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("User not found"))
-        }
-    }
-    fn change_room_name(&self, old_name: &str, new_name: &str) -> anyhow::Result<()> {
-        let mut write_guard = self.0.write().unwrap();
-        if let Some(room) = write_guard.remove(old_name) {
-            write_guard.insert(new_name.to_owned(), room);
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Room not found"))
-        }
-    }
-    fn list_users(&self, room_name: &str) -> Vec<String> {
-        let mut users = Vec::new();
-        let read_guard = self.0.read().unwrap();
-        for user in read_guard.get(room_name).unwrap().users.iter() {
-            users.push(user.to_owned());
-        }
-        users
-    }
-    fn get_existing(&self) -> Vec<(String, usize)> {
-        let mut rooms = Vec::new();
-        for s in self.0.read().unwrap().iter() {
-            rooms.push((s.0.clone(), s.1.tx.receiver_count()));
-        }
-        rooms.sort_by(|a, b| {
-            use std::cmp::Ordering::*;
-            match b.1.cmp(&a.1) {
-                Equal => a.0.cmp(&b.0),
-                ordering => ordering,
-            }
-        });
-        return rooms;
-    }
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:6142").await?;
-    let rooms = Rooms::new();
-    let names = Names::new();
-
-    let app = Router::new()
-        .route("/ws", routing::any(ws_handler))
-        .with_state((rooms, names));
-
-    axum::serve(listener, app).await?;
-    Ok(())
-}
-
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State((rooms, names)): State<(Rooms, Names)>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| async move {
-        if let Err(e) = process(socket, rooms, names).await {
-            eprintln!("connection error: {e}");
-        }
-    })
-}
-
-async fn process(mut socket: WebSocket, rooms: Rooms, existing: Names) -> anyhow::Result<()> {
-    let mut user_name = existing.get_unique();
-    let mut room_name = MAIN.to_owned();
-    let mut tx = rooms.join(&room_name, &user_name);
-    let mut rx = tx.subscribe();
-
-    let _ = tx.send(ChatMessage::new(format!(
-        "{user_name} has joined the chat."
-    )));
-
-    let _ = socket.send(Message::Text(HELP_MSG.into())).await;
-
-    let mut heartbeat = time::interval(time::Duration::from_secs(15));
-    heartbeat.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
-
-    // main loop returns Result so `b!` can break with Err
-    let result: anyhow::Result<()> = loop {
-        tokio::select! {
-            msg = socket.recv() => {
-                let msg = match msg {
-                    Some(msg) => b!(msg),
-                    None => break Ok(()), // client closed
-                };
-
-                let user_msg = match msg {
-                    Message::Text(t) => t,
-                    Message::Binary(_) => continue,
-                    Message::Ping(_) => continue,
-                    Message::Pong(_) => {
-                        b!(socket.send(Message::Text(format!("Received pong from {}", user_name).into())).await);
-                        continue;
-                    }
-                    Message::Close(_) => break Ok(()),
-                };
-
-                if user_msg.starts_with("/join") {
-
-                    let mut itr = user_msg.split_ascii_whitespace();
-                    itr.next();
-                    let new_room = itr.collect::<Vec<&str>>().join(" ");
-
-                    if new_room == room_name {
-                        b!(socket.send(Message::Text("You are already in this room.".into())).await);
-                        continue;
-                    }
-
-                    b!(tx.send(ChatMessage::new(format!("{user_name} has left {room_name}."))));
-                    tx = rooms.change(&room_name, &new_room, &user_name);
-                    rx = tx.subscribe();
-                    room_name = new_room;
-                    b!(tx.send(ChatMessage::new(format!("{user_name} has joined {room_name}."))));
-                }
-                else if user_msg.starts_with("/name") {
-                    let mut itr = user_msg.split_ascii_whitespace();
-                    itr.next();
-                    let new_name = itr.collect::<Vec<&str>>().join(" ");
-                    let changed_name = existing.insert(new_name.clone());
-                    if changed_name {
-                        existing.remove(&user_name);
-                        b!(rooms.change_name(&room_name, &user_name, &new_name));
-                        b!(tx.send(ChatMessage::new(format!("{user_name} is now {new_name}"))));
-                        b!(tx.send(ChatMessage::new(format!("Current names in room: {:?}", rooms.list_users(&room_name)))));
-                        user_name = new_name;
-                    }
-                    else {
-                        b!(socket.send(Message::Text("Sorry, that name is taken.".into())).await);
-                    }
-                }
-                else if user_msg.starts_with("/allusers") {
-                    let users_str = format!("All users: {:?}", existing.get_existing());
-                    b!(socket.send(Message::Text(users_str.into())).await);
-                }
-                else if user_msg.starts_with("/users") {
-                    let users_str = format!("Users in current room: {:?}", rooms.list_users(&room_name));
-                    b!(socket.send(Message::Text(users_str.into())).await);
-                }
-                else if user_msg.starts_with("/rooms") {
-                    let rooms_list = rooms
-                        .get_existing()
-                        .into_iter()
-                        .map(|(name, count)| format!("{name} ({count})"))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    let rooms_str = format!("Current rooms: {rooms_list}");
-                    b!(socket.send(Message::Text(rooms_str.into())).await);
-                }
-                else if user_msg.starts_with("/renameroom ") {
-                    let mut itr = user_msg.split_ascii_whitespace();
-                    itr.next();
-                    let new_room_name = itr.collect::<Vec<&str>>().join(" ");
-
-                    if rooms.0.read().unwrap().contains_key(&new_room_name) {
-                        b!(socket.send(Message::Text("Room name already exists.".into())).await);
-                        continue;
-                    }
-
-                    b!(rooms.change_room_name(&room_name, &new_room_name));
-                    b!(tx.send(ChatMessage::new(format!("Room {room_name} has been renamed to {new_room_name}."))));
-                    room_name = new_room_name;
-                }
-                else if user_msg.starts_with("/help") {
-                    b!(socket.send(Message::Text(HELP_MSG.into())).await);
-                }
-                else if user_msg.starts_with("/quit") {
-                    break Ok(());
-                }
-                else {
-                    b!(tx.send(ChatMessage::new(format!("{user_name}: {user_msg}"))));
-                }
-            },
-
-            peer_msg = rx.recv() => {
-                let peer_msg = b!(peer_msg);
-                // Send machine-readable JSON so load tests can parse timestamps reliably
-                match serde_json::to_string(&peer_msg) {
-                    Ok(json) => {
-                        b!(socket.send(Message::Text(json.into())).await);
-                    }
-                    Err(_) => {
-                        // fallback to formatted text (timestamp is milliseconds)
-                        let ts = peer_msg.timestamp as i64;
-                        let secs = ts / 1000;
-                        let nsecs = ((ts % 1000) * 1_000_000) as u32;
-                        let dt = Utc.timestamp_opt(secs, nsecs).single().unwrap();
-                        let formatted_date = dt.format("%Y-%m-%d %H:%M:%S").to_string();
-                        let millis = (ts % 1000).abs();
-                        let formatted_time = format!("{}.{} UTC", formatted_date, format!("{:03}", millis));
-                        let output_msg = format!("[{}] {}", formatted_time, peer_msg.message);
-                        b!(socket.send(Message::Text(output_msg.into())).await);
-                    }
-                }
-            },
-            _ = heartbeat.tick() => {
-                b!(socket.send(Message::Ping(Bytes::from("ping"))).await);
-            }
-        }
-    };
-
-    let _ = tx.send(ChatMessage::new(format!("{user_name} has left the chat.")));
-    existing.remove(&user_name);
-    rooms.leave(&room_name, &user_name);
-    result
-}
+use anyhow::Context;
+use axum::Json;
+use axum::extract::{
+    ConnectInfo, Path as UrlPath, State,
+    ws::{Message, WebSocket, WebSocketUpgrade},
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Router, routing};
+use axum_server::tls_rustls::RustlsConfig;
+use bytes::Bytes;
+use chrono::{FixedOffset, TimeZone, Utc};
+use clap::Parser;
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{
+    self, Receiver, Sender,
+    error::{RecvError, SendError},
+};
+use tokio::sync::{Semaphore, mpsc};
+use tokio::time;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{StreamExt, StreamMap};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+use rust_final_project::{people_count, random_handle, random_name};
+
+macro_rules! b {
+    ($result:expr) => {
+        match $result {
+            Ok(ok) => ok,
+            Err(err) => break Err(err.into()),
+        }
+    };
+}
+
+// sent on connect, before the help text, unless --welcome names a file to use instead
+const DEFAULT_WELCOME_MSG: &str = "Welcome to Chatski!";
+const TOO_FAST_MSG: &str = "You're sending messages too fast.";
+const CREATING_TOO_FAST_MSG: &str =
+    "You're creating rooms too fast; join an existing room or try again later.";
+const NOT_DELIVERED_MSG: &str = "Message not delivered (no one is listening).";
+const ROOM_GONE_MSG: &str = "Room no longer exists.";
+// number of recent messages each room keeps for replay to new joiners
+const HISTORY_LEN: usize = 50;
+// longest chat or whisper text accepted from a client, in bytes
+const MAX_MESSAGE_LEN: usize = 2000;
+// longest user name accepted by /name, in characters
+const MAX_NAME_LEN: usize = 32;
+// longest room name accepted by /join and /renameroom, in characters
+const MAX_ROOM_NAME_LEN: usize = 32;
+// random picks from each name pool before get_unique falls back to the next
+const NAME_ATTEMPTS: usize = 100;
+// names the server speaks as, so no user may take them (compared case-insensitively)
+const RESERVED_NAMES: &[&str] = &["admin", "system", "server"];
+// colors handed to senders for clients to tell speakers apart, picked by `user_color`
+const USER_COLORS: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+];
+// each connection may send RATE_LIMIT_MESSAGES chat messages, whispers or other commands
+// that broadcast per RATE_LIMIT_WINDOW
+const RATE_LIMIT_MESSAGES: u32 = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(3);
+// window over which each connection's --room-creations allowance is counted
+const ROOM_CREATION_WINDOW: Duration = Duration::from_secs(60);
+// repeated /typing signals from one connection within this long are dropped
+const TYPING_DEBOUNCE: Duration = Duration::from_secs(2);
+// how long clients get to read the shutdown notice before connections are closed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+// connections that send nothing (not even a pong) for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// number of recent ping round trips averaged for /ping
+const LATENCY_SAMPLES: usize = 8;
+// most users a room may hold; the default room has its own cap, where None means unlimited
+const ROOM_CAPACITY: usize = 50;
+const DEFAULT_ROOM_CAPACITY: Option<usize> = None;
+// rooms listed per page of /rooms
+const ROOMS_PAGE_SIZE: usize = 20;
+// how long an /invite code stays redeemable
+const INVITE_TTL: Duration = Duration::from_secs(15 * 60);
+// length of an /invite code, in alphanumeric characters
+const INVITE_CODE_LEN: usize = 8;
+// whispers kept per pair of users for /dm-history
+const DM_HISTORY_LEN: usize = 20;
+// how long after a connection closes its /resume token still reclaims its name and room
+const RESUME_TTL: Duration = Duration::from_secs(5 * 60);
+// a resume token stands in for the user's identity, so it is much longer than an invite code
+const RESUME_TOKEN_LEN: usize = 24;
+// most rooms one connection may /subscribe to besides the one it is in
+const MAX_SUBSCRIPTIONS: usize = 10;
+// longest file name accepted in a file frame, in bytes, which keeps the relayed header
+// well within its u16 length
+const MAX_FILENAME_LEN: usize = 255;
+// files a user may have waiting to be written to their socket before senders are told to retry
+const FILE_QUEUE_LEN: usize = 4;
+// most choices a /poll may offer
+const MAX_POLL_OPTIONS: usize = 10;
+// presence events buffered for /presence listeners that fall behind
+const PRESENCE_CAPACITY: usize = 64;
+
+// lets clients tell messages apart without parsing `message`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MessageKind {
+    Chat,
+    System,
+    Whisper,
+    Join,
+    Leave,
+    Typing,
+    // replaces the text of the earlier message with the same `seq`
+    Edit,
+    // withdraws the earlier message with the same `seq`
+    Delete,
+    // a user connecting to or disconnecting from the server, sent to /presence listeners
+    Presence,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct ChatMessage {
+    message: String,
+    timestamp: i64,
+    // the user who caused the message; None when it comes from the server itself
+    sender: Option<String>,
+    kind: MessageKind,
+    // position in the room's stream, from 1, so clients can spot gaps; 0 for whispers
+    seq: u64,
+    // members of the room @mentioned in a chat message, for clients to highlight
+    mentions: Vec<String>,
+    // the sender's `user_color`; absent from snapshots taken before colors existed
+    #[serde(default)]
+    color: Option<String>,
+}
+
+impl ChatMessage {
+    fn new(kind: MessageKind, sender: Option<&str>, message: String) -> Self {
+        ChatMessage {
+            message,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64,
+            sender: sender.map(str::to_owned),
+            kind,
+            seq: 0,
+            mentions: Vec::new(),
+            color: sender.map(|sender| user_color(sender).to_owned()),
+        }
+    }
+    fn system(message: String) -> Self {
+        Self::new(MessageKind::System, None, message)
+    }
+}
+
+struct RateLimiter {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= RATE_LIMIT_WINDOW {
+            self.window_start = now;
+            self.count = 0;
+        }
+        if self.count < RATE_LIMIT_MESSAGES {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+    // true once a new window would start, so forgetting this limiter changes nothing
+    fn idle(&self) -> bool {
+        self.window_start.elapsed() >= RATE_LIMIT_WINDOW
+    }
+}
+
+// when this connection created its most recent rooms, oldest first, within
+// ROOM_CREATION_WINDOW; a limit of 0 means no limit
+struct RoomCreations {
+    times: VecDeque<Instant>,
+    limit: usize,
+}
+
+impl RoomCreations {
+    fn new(limit: usize) -> Self {
+        RoomCreations {
+            times: VecDeque::new(),
+            limit,
+        }
+    }
+    fn allow(&mut self) -> bool {
+        while self
+            .times
+            .front()
+            .is_some_and(|time| time.elapsed() >= ROOM_CREATION_WINDOW)
+        {
+            self.times.pop_front();
+        }
+        self.limit == 0 || self.times.len() < self.limit
+    }
+    fn record(&mut self) {
+        self.times.push_back(Instant::now());
+    }
+}
+
+// round-trip times of the most recent heartbeat pings
+struct Latency {
+    samples: VecDeque<Duration>,
+}
+
+impl Latency {
+    fn new() -> Self {
+        Latency {
+            samples: VecDeque::with_capacity(LATENCY_SAMPLES),
+        }
+    }
+    fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+    fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+    fn average(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+}
+
+// heartbeat pings sent since the client last answered one
+struct UnansweredPings {
+    count: u32,
+    limit: u32,
+}
+
+impl UnansweredPings {
+    fn new(limit: u32) -> Self {
+        UnansweredPings { count: 0, limit }
+    }
+    // true once `limit` pings in a row have gone unanswered, and the connection is presumed dead
+    fn exhausted(&self) -> bool {
+        self.count >= self.limit
+    }
+    fn sent(&mut self) {
+        self.count += 1;
+    }
+    fn answered(&mut self) {
+        self.count = 0;
+    }
+}
+
+// a client's WebSocket, whose writes give up after `timeout` so a client that has
+// stopped reading can't stall its connection's loop
+struct TimedSocket {
+    socket: WebSocket,
+    timeout: Duration,
+}
+
+impl TimedSocket {
+    async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        match time::timeout(self.timeout, self.socket.send(msg)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => {
+                counter!("chat_slow_clients_total").increment(1);
+                Err(anyhow::anyhow!("send timed out, client is not reading"))
+            }
+        }
+    }
+    async fn recv(&mut self) -> Option<Result<Message, axum::Error>> {
+        self.socket.recv().await
+    }
+}
+
+// every room's broadcasts merged into one stream, for an admin's /monitor; rooms are
+// keyed by an id of their own, since their names change with /renameroom
+struct Monitor {
+    streams: StreamMap<u64, BroadcastStream<ChatMessage>>,
+    // kept to spot rooms already monitored, and deleted ones, by channel
+    channels: HashMap<u64, Sender<ChatMessage>>,
+    next_id: u64,
+}
+
+impl Monitor {
+    fn new() -> Self {
+        Monitor {
+            streams: StreamMap::new(),
+            channels: HashMap::new(),
+            next_id: 0,
+        }
+    }
+    // starts listening to rooms created since the last sync and drops deleted ones;
+    // holding a room's sender keeps its channel open, so deletion is not seen otherwise
+    fn sync(&mut self, rooms: &Rooms) {
+        let current = rooms.channels();
+        self.channels.retain(|id, known| {
+            let kept = current.iter().any(|tx| tx.same_channel(known));
+            if !kept {
+                self.streams.remove(id);
+            }
+            kept
+        });
+        for tx in current {
+            if self.channels.values().any(|known| known.same_channel(&tx)) {
+                continue;
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            self.streams
+                .insert(id, BroadcastStream::new(tx.subscribe()));
+            self.channels.insert(id, tx);
+        }
+    }
+}
+
+// instructions one connection's process() loop sends to another's
+#[derive(Debug)]
+enum Control {
+    // the owner of `room` removed this user from it
+    Kick { room: String },
+    // another member renamed the room this user is in
+    RoomRenamed { from: String, to: String },
+    // this connection found `room` gone, or no longer listing this user
+    RoomGone { room: String },
+}
+
+// the ways other connections can reach a user
+#[derive(Clone, Debug)]
+struct UserHandle {
+    // personal inbox for whispers
+    inbox: Sender<ChatMessage>,
+    control: mpsc::UnboundedSender<Control>,
+    // files other users are sending this one
+    files: mpsc::Sender<FileTransfer>,
+}
+
+// a file on its way from one user's binary frame to another's
+#[derive(Debug)]
+struct FileTransfer {
+    from: String,
+    filename: String,
+    data: Bytes,
+}
+
+// binary frames are a big-endian u16 header length, that many bytes of JSON header,
+// then the file itself; clients send a FileRequest header and receive a FileNotice
+#[derive(serde::Deserialize)]
+struct FileRequest {
+    to: String,
+    filename: String,
+}
+
+#[derive(serde::Serialize)]
+struct FileNotice<'a> {
+    from: &'a str,
+    filename: &'a str,
+}
+
+fn parse_file_frame(frame: &Bytes) -> Option<(FileRequest, Bytes)> {
+    let header_len = usize::from(u16::from_be_bytes(frame.get(..2)?.try_into().ok()?));
+    let header = frame.get(2..2 + header_len)?;
+    let request = serde_json::from_slice(header).ok()?;
+    Some((request, frame.slice(2 + header_len..)))
+}
+
+// None if the header is too long for its length prefix
+fn encode_file_frame(notice: &FileNotice, data: &[u8]) -> Option<Bytes> {
+    let header = serde_json::to_vec(notice).expect("file notice serializes");
+    let header_len = u16::try_from(header.len()).ok()?;
+    let mut frame = Vec::with_capacity(2 + header.len() + data.len());
+    frame.extend_from_slice(&header_len.to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(data);
+    Some(frame.into())
+}
+
+// the recipient saves the file under this name, so keep it to a plain file name
+fn validate_filename(filename: &str) -> Result<(), String> {
+    if filename.len() > MAX_FILENAME_LEN {
+        Err(format!(
+            "File name too long (max {MAX_FILENAME_LEN} bytes)."
+        ))
+    } else if filename.is_empty()
+        || filename.contains(['/', '\\'])
+        || filename.chars().any(char::is_control)
+    {
+        Err("Invalid file name.".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct NameEntry {
+    // the name with the casing the user chose
+    display: String,
+    handle: UserHandle,
+}
+
+// a name given up by disconnecting or renaming, which only the connection that held it
+// may take again until the cooldown passes
+#[derive(Clone, Debug)]
+struct Released {
+    at: Instant,
+    by: Sender<ChatMessage>,
+}
+
+#[derive(Clone, Debug)]
+struct Names {
+    // keyed by `name_key` so "Alice" and "alice" cannot coexist
+    existing: Arc<Mutex<HashMap<String, NameEntry>>>,
+    // also keyed by `name_key`; always locked after `existing`
+    released: Arc<Mutex<HashMap<String, Released>>>,
+    cooldown: Duration,
+}
+
+fn name_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+// membership and server notices still get through, so the room's state stays clear;
+// `silenced` holds name keys, and follows the name rather than whoever holds it
+fn is_silenced(silenced: &HashSet<String>, msg: &ChatMessage) -> bool {
+    !matches!(
+        msg.kind,
+        MessageKind::Join | MessageKind::Leave | MessageKind::System | MessageKind::Presence
+    ) && msg
+        .sender
+        .as_deref()
+        .is_some_and(|sender| silenced.contains(&name_key(sender)))
+}
+
+// nobody may be listening, which is fine
+fn announce_presence(presence: &Sender<ChatMessage>, user_name: &str, online: bool) {
+    let status = if online { "online" } else { "offline" };
+    let _ = presence.send(ChatMessage::new(
+        MessageKind::Presence,
+        Some(user_name),
+        format!("{user_name} is {status}."),
+    ));
+}
+
+// `"Question in quotes" option option ...`; a one-word question needs no quotes
+fn parse_poll(args: &str) -> Option<(String, Vec<String>)> {
+    let args = args.trim_start();
+    let (question, rest) = match args.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"')?,
+        None => args.split_once(char::is_whitespace)?,
+    };
+    let question = question.trim();
+    let options = rest
+        .split_ascii_whitespace()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if question.is_empty() || !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return None;
+    }
+    Some((question.to_owned(), options))
+}
+
+// a hash of the name rather than per-connection state, so a user keeps their color
+// across reconnects and everyone agrees on it; a new name usually brings a new color
+fn user_color(name: &str) -> &'static str {
+    let hash = name_key(name).bytes().fold(0u32, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte.into())
+    });
+    USER_COLORS[hash as usize % USER_COLORS.len()]
+}
+
+fn is_reserved(name: &str) -> bool {
+    let key = name_key(name);
+    RESERVED_NAMES.iter().any(|reserved| *reserved == key)
+}
+
+impl Names {
+    fn new(cooldown: Duration) -> Self {
+        Names {
+            existing: Arc::new(Mutex::new(HashMap::new())),
+            released: Arc::new(Mutex::new(HashMap::new())),
+            cooldown,
+        }
+    }
+    // the inbox of whoever released `key`, while it is still cooling down
+    fn released_by(&self, key: &str) -> Option<Sender<ChatMessage>> {
+        let mut released = self.released.lock().unwrap();
+        released.retain(|_, release| release.at.elapsed() < self.cooldown);
+        released.get(key).map(|release| release.by.clone())
+    }
+    // true if `key` was released too recently for anyone but its last holder to take
+    fn cooling_down(&self, key: &str, handle: &UserHandle) -> bool {
+        self.released_by(key)
+            .is_some_and(|by| !by.same_channel(&handle.inbox))
+    }
+    // true if a connected user holds `name`, or gave it up too recently for another to use
+    fn in_use(&self, name: &str) -> bool {
+        let key = name_key(name);
+        let existing = self.existing.lock().unwrap();
+        existing.contains_key(&key) || self.released_by(&key).is_some()
+    }
+    fn release(&self, key: String, handle: &UserHandle) {
+        if self.cooldown.is_zero() {
+            return;
+        }
+        self.released.lock().unwrap().insert(
+            key,
+            Released {
+                at: Instant::now(),
+                by: handle.inbox.clone(),
+            },
+        );
+    }
+    fn insert(&self, str: String, handle: UserHandle) -> bool {
+        let key = name_key(&str);
+        let mut existing = self.existing.lock().unwrap();
+        if self.cooling_down(&key, &handle) {
+            return false;
+        }
+        match existing.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(NameEntry {
+                    display: str,
+                    handle,
+                });
+                true
+            }
+        }
+    }
+    fn remove(&self, str: &str) -> bool {
+        let key = name_key(str);
+        let mut existing = self.existing.lock().unwrap();
+        let Some(entry) = existing.remove(&key) else {
+            return false;
+        };
+        self.release(key, &entry.handle);
+        true
+    }
+    // swaps `old` for `new` in one step; a user may re-case their own name
+    fn rename(&self, old: &str, new: String, handle: &UserHandle) -> bool {
+        self.swap(old, new, handle, false)
+    }
+    // like `rename`, for a user who proved with /resume that the name was theirs
+    fn reclaim(&self, old: &str, new: String, handle: &UserHandle) -> bool {
+        self.swap(old, new, handle, true)
+    }
+    fn swap(&self, old: &str, new: String, handle: &UserHandle, ignore_cooldown: bool) -> bool {
+        let mut existing = self.existing.lock().unwrap();
+        let new_key = name_key(&new);
+        if let Some(entry) = existing.get(&new_key)
+            && !entry.handle.inbox.same_channel(&handle.inbox)
+        {
+            return false;
+        }
+        if !ignore_cooldown && self.cooling_down(&new_key, handle) {
+            return false;
+        }
+        let old_key = name_key(old);
+        existing.remove(&old_key);
+        if old_key != new_key {
+            self.release(old_key, handle);
+        }
+        existing.insert(
+            new_key,
+            NameEntry {
+                display: new,
+                handle: handle.clone(),
+            },
+        );
+        true
+    }
+    // a famous name, or a handle once those seem to be taken or cooling down; numbered
+    // guest names come last, since --max-connections may exceed both pools
+    fn get_unique(&self, handle: &UserHandle) -> String {
+        std::iter::repeat_with(random_name)
+            .take(NAME_ATTEMPTS)
+            .chain(std::iter::repeat_with(random_handle).take(NAME_ATTEMPTS))
+            .chain((1..).map(|n| format!("guest-{n}")))
+            .find(|name| !is_reserved(name) && self.insert(name.clone(), handle.clone()))
+            .expect("numbered guest names never run out")
+    }
+    fn handle(&self, name: &str) -> Option<UserHandle> {
+        self.existing
+            .lock()
+            .unwrap()
+            .get(&name_key(name))
+            .map(|entry| entry.handle.clone())
+    }
+    fn count(&self) -> usize {
+        self.existing.lock().unwrap().len()
+    }
+    fn get_existing(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for entry in self.existing.lock().unwrap().values() {
+            names.push(entry.display.clone());
+        }
+        names
+    }
+    // names may contain spaces, so the longest run of leading words naming a user wins
+    fn find_user(&self, words: &[&str]) -> Option<(usize, NameEntry)> {
+        let existing = self.existing.lock().unwrap();
+        (1..=words.len()).rev().find_map(|n| {
+            existing
+                .get(&name_key(&words[..n].join(" ")))
+                .map(|entry| (n, entry.clone()))
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct UserState {
+    away: Option<String>,
+}
+
+// salted SHA-256 of a room password; the plaintext is never stored
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct PasswordHash {
+    salt: [u8; 16],
+    digest: [u8; 32],
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Self {
+        let mut salt = [0; 16];
+        fastrand::fill(&mut salt);
+        let digest = Self::digest(&salt, password);
+        PasswordHash { salt, digest }
+    }
+    fn digest(salt: &[u8], password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(password.as_bytes())
+            .finalize()
+            .into()
+    }
+    fn matches(&self, password: &str) -> bool {
+        Self::digest(&self.salt, password) == self.digest
+    }
+}
+
+struct Room {
+    tx: Sender<ChatMessage>,
+    users: HashMap<String, UserState>,
+    history: VecDeque<ChatMessage>,
+    topic: Option<String>,
+    // set by whoever creates the room; None means anyone may join
+    password: Option<PasswordHash>,
+    // may /kick and /ban; the creator, or the first to join a restored room, handed on when
+    // they leave. The default room has none
+    owner: Option<String>,
+    // `name_key`s refused by `join`
+    banned: HashSet<String>,
+    // sequence number given to the next message sent in this room
+    next_seq: AtomicU64,
+    // messages successfully broadcast here; kept across /renameroom since the Room moves whole
+    messages: AtomicU64,
+    // the latest /poll; starting another replaces it
+    poll: Option<Poll>,
+}
+
+// a question put to the room with /poll
+struct Poll {
+    question: String,
+    options: Vec<String>,
+    // each voter's `name_key` and the index of their choice; voting again replaces it
+    votes: HashMap<String, usize>,
+}
+
+impl Poll {
+    fn tally(&self) -> String {
+        let mut counts = vec![0; self.options.len()];
+        for &choice in self.votes.values() {
+            counts[choice] += 1;
+        }
+        let mut lines = vec![format!("Poll: {}", self.question)];
+        for (i, (option, count)) in self.options.iter().zip(counts).enumerate() {
+            let votes = if count == 1 { "vote" } else { "votes" };
+            lines.push(format!("  {}. {option} - {count} {votes}", i + 1));
+        }
+        lines.join("\n")
+    }
+}
+
+// what a connection receives when it enters a room
+struct Joined {
+    tx: Sender<ChatMessage>,
+    rx: Receiver<ChatMessage>,
+    history: Vec<ChatMessage>,
+    topic: Option<String>,
+    // whether this join brought the room into existence
+    created: bool,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(32);
+        let users = HashMap::new();
+        let history = VecDeque::with_capacity(HISTORY_LEN);
+        Self {
+            tx,
+            users,
+            history,
+            topic: None,
+            password: None,
+            owner: None,
+            banned: HashSet::new(),
+            next_seq: AtomicU64::new(1),
+            messages: AtomicU64::new(0),
+            poll: None,
+        }
+    }
+    // where the chat message numbered `seq` sits in history, if `user_name` sent it
+    fn own_message(
+        &self,
+        user_name: &str,
+        seq: u64,
+        action: &'static str,
+    ) -> Result<usize, RoomError> {
+        let Some(index) = self.history.iter().position(|msg| msg.seq == seq) else {
+            return Err(RoomError::NotInHistory { seq });
+        };
+        let msg = &self.history[index];
+        if msg.kind != MessageKind::Chat || msg.sender.as_deref() != Some(user_name) {
+            return Err(RoomError::NotYours { action });
+        }
+        Ok(index)
+    }
+}
+
+// the room the caller holds `tx` for, unless it was renamed or replaced meanwhile
+fn own_room<'a>(
+    map: &'a mut HashMap<String, Room>,
+    room_name: &str,
+    tx: &Sender<ChatMessage>,
+) -> Result<&'a mut Room, RoomError> {
+    map.get_mut(room_name)
+        .filter(|room| room.tx.same_channel(tx))
+        .ok_or(RoomError::RoomNotFound)
+}
+
+// the parts of a room that outlive a restart; its users reconnect on their own
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RoomSnapshot {
+    name: String,
+    topic: Option<String>,
+    history: Vec<ChatMessage>,
+    password: Option<PasswordHash>,
+}
+
+// what a joiner offers to get into a room
+#[derive(Clone, Copy)]
+enum Admission<'a> {
+    // locks the room when it creates it, and is checked against the lock otherwise
+    Password(Option<&'a str>),
+    // a redeemed /invite code for the room whose channel is this; skips the password
+    // only for that room, not one since created under its name
+    Invite(&'a Sender<ChatMessage>),
+    // a /resume into the room whose channel is this; skips the password only if the room
+    // is still that one, rather than another since created under its name
+    Rejoin(&'a Sender<ChatMessage>),
+}
+
+#[derive(Debug)]
+enum JoinError {
+    RoomFull,
+    IncorrectPassword,
+    Banned,
+    NoSuchRoom,
+    // only members may read a room with a password, so it cannot be subscribed to
+    Locked,
+    // --max-rooms rooms already exist, so no new one may be created
+    RoomLimit,
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::RoomFull => write!(f, "Room is full."),
+            JoinError::IncorrectPassword => write!(f, "Incorrect password."),
+            JoinError::Banned => write!(f, "You are banned from this room."),
+            JoinError::NoSuchRoom => write!(f, "That room no longer exists."),
+            JoinError::Locked => write!(f, "That room is locked; /join it with its password."),
+            JoinError::RoomLimit => write!(f, "Room limit reached."),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+// why a change to a room or one of its members was refused
+#[derive(Debug)]
+enum RoomError {
+    // gone, or renamed since the caller looked it up
+    RoomNotFound,
+    UserNotFound,
+    // another room already has the name asked for
+    NameTaken,
+    // the default room keeps its name, and no other room may take it
+    DefaultRoom,
+    NotInHistory { seq: u64 },
+    // the message was someone else's, or not a chat message
+    NotYours { action: &'static str },
+    NotOwner { command: &'static str },
+    AlreadyOwner,
+    NoPoll,
+    NoSuchOption { options: usize },
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomError::RoomNotFound => write!(f, "Room not found."),
+            RoomError::UserNotFound => write!(f, "No such user in this room."),
+            RoomError::NameTaken => write!(f, "Room name already exists."),
+            RoomError::DefaultRoom => {
+                write!(f, "The default room cannot be renamed, or renamed to.")
+            }
+            RoomError::NotInHistory { seq } => {
+                write!(
+                    f,
+                    "There is no message {seq} in this room's recent history."
+                )
+            }
+            RoomError::NotYours { action } => write!(f, "You can only {action} your own messages."),
+            RoomError::NotOwner { command } => write!(f, "Only the room owner may use {command}."),
+            RoomError::AlreadyOwner => write!(f, "You already own this room."),
+            RoomError::NoPoll => write!(f, "There is no poll in this room."),
+            RoomError::NoSuchOption { options } => {
+                write!(f, "Choose an option from 1 to {options}.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+#[derive(Clone)]
+struct Rooms {
+    map: Arc<RwLock<HashMap<String, Room>>>,
+    // messages broadcast in any room since startup, for /stats
+    sent: Arc<AtomicU64>,
+    // set by --audit-log
+    audit: Option<AuditLog>,
+    // set by --max-rooms; the default room may always be created
+    max_rooms: Option<usize>,
+    // where connections start, and where they land when removed from a room (--default-room)
+    default_room: Arc<str>,
+}
+impl Rooms {
+    fn new(audit: Option<AuditLog>, max_rooms: Option<usize>, default_room: &str) -> Self {
+        Self {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            sent: Arc::new(AtomicU64::new(0)),
+            audit,
+            max_rooms,
+            default_room: default_room.into(),
+        }
+    }
+    fn default_room(&self) -> &str {
+        &self.default_room
+    }
+    fn capacity(&self, room_name: &str) -> Option<usize> {
+        if room_name == self.default_room() {
+            DEFAULT_ROOM_CAPACITY
+        } else {
+            Some(ROOM_CAPACITY)
+        }
+    }
+    // subscribing under the write guard means the returned history and receiver
+    // neither overlap nor leave a gap, since `send` records under the same guard
+    fn join(
+        &self,
+        room_name: &str,
+        user_name: &str,
+        admission: Admission,
+    ) -> Result<Joined, JoinError> {
+        let mut write_guard = self.map.write().unwrap();
+        // counted under the write guard so concurrent joins cannot create rooms past the cap
+        let at_limit = room_name != self.default_room()
+            && self
+                .max_rooms
+                .is_some_and(|max_rooms| write_guard.len() >= max_rooms);
+        let created = !write_guard.contains_key(room_name);
+        let room = match (write_guard.entry(room_name.to_owned()), admission) {
+            (Entry::Occupied(entry), _) => entry.into_mut(),
+            (Entry::Vacant(_), Admission::Invite(_)) => return Err(JoinError::NoSuchRoom),
+            (Entry::Vacant(_), _) if at_limit => return Err(JoinError::RoomLimit),
+            (Entry::Vacant(entry), _) => {
+                let room = entry.insert(Room::new());
+                // the default room is where everyone lands, so it is never locked
+                if let Admission::Password(password) = admission
+                    && room_name != self.default_room()
+                {
+                    room.password = password.map(PasswordHash::new);
+                }
+                room
+            }
+        };
+        if room.banned.contains(&name_key(user_name)) {
+            return Err(JoinError::Banned);
+        }
+        if let Some(hash) = &room.password {
+            let admitted = match admission {
+                Admission::Password(password) => {
+                    password.is_some_and(|password| hash.matches(password))
+                }
+                Admission::Invite(tx) | Admission::Rejoin(tx) => room.tx.same_channel(tx),
+            };
+            if !admitted {
+                return Err(JoinError::IncorrectPassword);
+            }
+        }
+        // checked under the write guard so concurrent joins cannot overfill the room
+        if let Some(capacity) = self.capacity(room_name)
+            && room.users.len() >= capacity
+        {
+            return Err(JoinError::RoomFull);
+        }
+        counter!("chat_room_joins_total").increment(1);
+        // only the room's creator, or the first to join a restored room, finds it unowned
+        if room.owner.is_none() && room_name != self.default_room() {
+            room.owner = Some(user_name.to_owned());
+        }
+        room.users
+            .insert(user_name.to_owned(), UserState::default());
+        Ok(Joined {
+            tx: room.tx.clone(),
+            rx: room.tx.subscribe(),
+            history: room.history.iter().cloned().collect(),
+            topic: room.topic.clone(),
+            created,
+        })
+    }
+    // lets `user_name` listen to a room they are not in; the returned sender is only
+    // kept to find the room again by channel, through renames or once it is deleted
+    fn subscribe(
+        &self,
+        room_name: &str,
+        user_name: &str,
+    ) -> Result<(Sender<ChatMessage>, Receiver<ChatMessage>), JoinError> {
+        let tx = self.channel_for(room_name, user_name)?;
+        let rx = tx.subscribe();
+        Ok((tx, rx))
+    }
+    // the channel of a room `user_name` may reach without joining it: one that is
+    // neither locked nor banned to them
+    fn channel_for(
+        &self,
+        room_name: &str,
+        user_name: &str,
+    ) -> Result<Sender<ChatMessage>, JoinError> {
+        let read_guard = self.map.read().unwrap();
+        let room = read_guard.get(room_name).ok_or(JoinError::NoSuchRoom)?;
+        if room.banned.contains(&name_key(user_name)) {
+            return Err(JoinError::Banned);
+        }
+        if room.password.is_some() {
+            return Err(JoinError::Locked);
+        }
+        Ok(room.tx.clone())
+    }
+    fn leave(&self, room_name: &str, user_name: &str) {
+        counter!("chat_room_leaves_total").increment(1);
+        let mut write_guard = self.map.write().unwrap();
+        let mut delete_room = false;
+        if let Some(room) = write_guard.get_mut(room_name) {
+            room.users.remove(user_name);
+            if room.owner.as_deref() == Some(user_name) {
+                room.owner = room.users.keys().next().cloned();
+            }
+            // subscribers hold receivers too, so count members rather than receivers
+            delete_room = room.users.is_empty();
+        }
+        if delete_room {
+            write_guard.remove(room_name);
+        }
+    }
+    // joins first so a refused user is still in `prev_room`
+    fn change(
+        &self,
+        prev_room: &str,
+        next_room: &str,
+        user_name: &str,
+        admission: Admission,
+    ) -> Result<Joined, JoinError> {
+        let joined = self.join(next_room, user_name, admission)?;
+        self.leave(prev_room, user_name);
+        Ok(joined)
+    }
+    fn change_name(
+        &self,
+        room_name: &str,
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        if let Some(room) = write_guard.get_mut(room_name) {
+            let state = room.users.remove(old_name).unwrap_or_default();
+            room.users.insert(new_name.to_owned(), state);
+            if room.owner.as_deref() == Some(old_name) {
+                room.owner = Some(new_name.to_owned());
+            }
+            if let Some(poll) = &mut room.poll
+                && let Some(choice) = poll.votes.remove(&name_key(old_name))
+            {
+                poll.votes.insert(name_key(new_name), choice);
+            }
+            Ok(())
+        } else {
+            Err(RoomError::RoomNotFound)
+        }
+    }
+    fn change_room_name(&self, old_name: &str, new_name: &str) -> Result<(), RoomError> {
+        // a locked room under the default name would turn every new connection away
+        if old_name == self.default_room() || new_name == self.default_room() {
+            return Err(RoomError::DefaultRoom);
+        }
+        let mut write_guard = self.map.write().unwrap();
+        // checked under the write guard so two renames cannot claim the same name
+        if write_guard.contains_key(new_name) {
+            return Err(RoomError::NameTaken);
+        }
+        let room = write_guard
+            .remove(old_name)
+            .ok_or(RoomError::RoomNotFound)?;
+        write_guard.insert(new_name.to_owned(), room);
+        Ok(())
+    }
+    // numbers the message and records it in the room's history before broadcasting
+    // it on `tx`; if the room was renamed out from under the caller the message is
+    // still sent, unnumbered
+    fn send(
+        &self,
+        room_name: &str,
+        tx: &Sender<ChatMessage>,
+        mut msg: ChatMessage,
+    ) -> Result<usize, SendError<ChatMessage>> {
+        let mut write_guard = self.map.write().unwrap();
+        let mut room = write_guard
+            .get_mut(room_name)
+            .filter(|room| room.tx.same_channel(tx));
+        if let Some(room) = room.as_mut() {
+            msg.seq = room.next_seq.fetch_add(1, Ordering::Relaxed);
+            if room.history.len() >= HISTORY_LEN {
+                room.history.pop_front();
+            }
+            room.history.push_back(msg.clone());
+        }
+        if let Some(audit) = &self.audit {
+            audit.record(room_name, &msg);
+        }
+        let sent = tx.send(msg);
+        if sent.is_ok() {
+            if let Some(room) = room {
+                room.messages.fetch_add(1, Ordering::Relaxed);
+            }
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            counter!("chat_messages_broadcast_total").increment(1);
+        }
+        sent
+    }
+    fn channel(&self, room_name: &str) -> Option<Sender<ChatMessage>> {
+        let read_guard = self.map.read().unwrap();
+        read_guard.get(room_name).map(|room| room.tx.clone())
+    }
+    // broadcasts an event about an earlier message, such as an edit, without numbering
+    // it or adding it to history
+    fn relay(&self, room_name: &str, tx: &Sender<ChatMessage>, event: ChatMessage) {
+        if let Some(audit) = &self.audit {
+            audit.record(room_name, &event);
+        }
+        let _ = tx.send(event);
+    }
+    // every notice about something a user did in a room goes through here, so they
+    // are all numbered, kept in history and tagged with `kind` and `sender` alike.
+    // Joins are announced in the destination room once the joiner has subscribed,
+    // so the joiner sees their own; leaves are announced in the source room, which
+    // the leaver stops reading in the same step, so they never see theirs
+    fn notify_room(
+        &self,
+        room_name: &str,
+        room_tx: &Sender<ChatMessage>,
+        kind: MessageKind,
+        sender: &str,
+        text: String,
+    ) -> Result<usize, SendError<ChatMessage>> {
+        self.send(
+            room_name,
+            room_tx,
+            ChatMessage::new(kind, Some(sender), text),
+        )
+    }
+    fn announce(&self, text: &str) {
+        // clone the senders so the read guard is released before `send` takes the write guard
+        let targets = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, room)| (name.clone(), room.tx.clone()))
+            .collect::<Vec<_>>();
+        for (name, tx) in targets {
+            let _ = self.send(&name, &tx, ChatMessage::system(text.to_owned()));
+        }
+    }
+    fn topic(&self, room_name: &str) -> Option<String> {
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .get(room_name)
+            .and_then(|room| room.topic.clone())
+    }
+    fn set_topic(&self, room_name: &str, topic: String) -> Result<(), RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        if let Some(room) = write_guard.get_mut(room_name) {
+            room.topic = Some(topic);
+            Ok(())
+        } else {
+            Err(RoomError::RoomNotFound)
+        }
+    }
+    fn start_poll(
+        &self,
+        room_name: &str,
+        tx: &Sender<ChatMessage>,
+        question: String,
+        options: Vec<String>,
+    ) -> Result<(), RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        let room = own_room(&mut write_guard, room_name, tx)?;
+        room.poll = Some(Poll {
+            question,
+            options,
+            votes: HashMap::new(),
+        });
+        Ok(())
+    }
+    // `choice` counts from 1, as the options are listed; returns the option voted for
+    fn vote(
+        &self,
+        room_name: &str,
+        tx: &Sender<ChatMessage>,
+        user_name: &str,
+        choice: usize,
+    ) -> Result<String, RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        let room = own_room(&mut write_guard, room_name, tx)?;
+        let Some(poll) = &mut room.poll else {
+            return Err(RoomError::NoPoll);
+        };
+        let Some(option) = choice.checked_sub(1).and_then(|i| poll.options.get(i)) else {
+            return Err(RoomError::NoSuchOption {
+                options: poll.options.len(),
+            });
+        };
+        let option = option.clone();
+        poll.votes.insert(name_key(user_name), choice - 1);
+        Ok(option)
+    }
+    fn poll_result(&self, room_name: &str) -> Option<String> {
+        let read_guard = self.map.read().unwrap();
+        read_guard.get(room_name)?.poll.as_ref().map(Poll::tally)
+    }
+    fn owner(&self, room_name: &str) -> Option<String> {
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .get(room_name)
+            .and_then(|room| room.owner.clone())
+    }
+    // the member's name as stored in the room, matched case-insensitively
+    fn find_member(&self, room_name: &str, user_name: &str) -> Option<String> {
+        let key = name_key(user_name);
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .get(room_name)?
+            .users
+            .keys()
+            .find(|user| name_key(user) == key)
+            .cloned()
+    }
+    // rewrites one of `user_name`'s chat messages still in the room's history, and
+    // returns the edit event announcing it, numbered with the edited message's seq
+    fn edit(
+        &self,
+        room_name: &str,
+        tx: &Sender<ChatMessage>,
+        user_name: &str,
+        seq: u64,
+        text: String,
+    ) -> Result<ChatMessage, RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        let room = own_room(&mut write_guard, room_name, tx)?;
+        let index = room.own_message(user_name, seq, "edit")?;
+        room.history[index].message = text.clone();
+        let mut event = ChatMessage::new(MessageKind::Edit, Some(user_name), text);
+        event.seq = seq;
+        Ok(event)
+    }
+    // like `edit`, but drops the message from history altogether
+    fn delete(
+        &self,
+        room_name: &str,
+        tx: &Sender<ChatMessage>,
+        user_name: &str,
+        seq: u64,
+    ) -> Result<ChatMessage, RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        let room = own_room(&mut write_guard, room_name, tx)?;
+        let index = room.own_message(user_name, seq, "delete")?;
+        room.history.remove(index);
+        let mut event = ChatMessage::new(
+            MessageKind::Delete,
+            Some(user_name),
+            format!("({user_name} deleted a message)"),
+        );
+        event.seq = seq;
+        Ok(event)
+    }
+    // checks and moves ownership under one lock, so two /transfer calls cannot both succeed
+    fn transfer(&self, room_name: &str, owner: &str, target: &str) -> Result<String, RoomError> {
+        let key = name_key(target);
+        let mut write_guard = self.map.write().unwrap();
+        let Some(room) = write_guard.get_mut(room_name) else {
+            return Err(RoomError::RoomNotFound);
+        };
+        if room.owner.as_deref() != Some(owner) {
+            return Err(RoomError::NotOwner {
+                command: "/transfer",
+            });
+        }
+        let Some(member) = room
+            .users
+            .keys()
+            .find(|user| name_key(user) == key)
+            .cloned()
+        else {
+            return Err(RoomError::UserNotFound);
+        };
+        if member == owner {
+            return Err(RoomError::AlreadyOwner);
+        }
+        room.owner = Some(member.clone());
+        Ok(member)
+    }
+    fn ban(&self, room_name: &str, user_name: &str) -> Result<(), RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        if let Some(room) = write_guard.get_mut(room_name) {
+            room.banned.insert(name_key(user_name));
+            Ok(())
+        } else {
+            Err(RoomError::RoomNotFound)
+        }
+    }
+    fn channels(&self) -> Vec<Sender<ChatMessage>> {
+        let read_guard = self.map.read().unwrap();
+        read_guard.values().map(|room| room.tx.clone()).collect()
+    }
+    fn name_of(&self, tx: &Sender<ChatMessage>) -> Option<String> {
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .iter()
+            .find(|(_, room)| room.tx.same_channel(tx))
+            .map(|(name, _)| name.clone())
+    }
+    // the current name of the room whose channel is `tx`, found by channel rather than
+    // by name so a rename in progress is not mistaken for the room going away; None if
+    // that room is gone or has lost track of the user
+    fn room_of(&self, tx: &Sender<ChatMessage>, user_name: &str) -> Option<String> {
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .iter()
+            .find(|(_, room)| room.tx.same_channel(tx) && room.users.contains_key(user_name))
+            .map(|(name, _)| name.clone())
+    }
+    // None if the room has been deleted or renamed since the caller looked it up
+    fn list_users(&self, room_name: &str) -> Option<Vec<String>> {
+        let mut users = Vec::new();
+        let read_guard = self.map.read().unwrap();
+        for user in read_guard.get(room_name)?.users.keys() {
+            users.push(user.to_owned());
+        }
+        Some(users)
+    }
+    fn describe_users(&self, room_name: &str) -> Option<Vec<String>> {
+        let mut users = Vec::new();
+        let read_guard = self.map.read().unwrap();
+        let room = read_guard.get(room_name)?;
+        for (user, state) in room.users.iter() {
+            let mut notes = Vec::new();
+            if room.owner.as_deref() == Some(user) {
+                notes.push("owner".to_owned());
+            }
+            match &state.away {
+                Some(reason) if reason.is_empty() => notes.push("away".to_owned()),
+                Some(reason) => notes.push(format!("away: {reason}")),
+                None => {}
+            }
+            users.push(if notes.is_empty() {
+                user.to_owned()
+            } else {
+                format!("{user} ({})", notes.join(", "))
+            });
+        }
+        Some(users)
+    }
+    fn set_away(
+        &self,
+        room_name: &str,
+        user_name: &str,
+        away: Option<String>,
+    ) -> Result<(), RoomError> {
+        let mut write_guard = self.map.write().unwrap();
+        let room = write_guard
+            .get_mut(room_name)
+            .ok_or(RoomError::RoomNotFound)?;
+        let state = room
+            .users
+            .get_mut(user_name)
+            .ok_or(RoomError::UserNotFound)?;
+        state.away = away;
+        Ok(())
+    }
+    // what RoomSnapshot keeps of every room, copied under one read guard
+    fn snapshot(&self) -> Vec<RoomSnapshot> {
+        let read_guard = self.map.read().unwrap();
+        read_guard
+            .iter()
+            .map(|(name, room)| RoomSnapshot {
+                name: name.clone(),
+                topic: room.topic.clone(),
+                history: room.history.iter().cloned().collect(),
+                password: room.password.clone(),
+            })
+            .collect()
+    }
+    // restored rooms start empty and stay until someone joins and the last one leaves
+    fn restore(&self, snapshots: Vec<RoomSnapshot>) {
+        let mut write_guard = self.map.write().unwrap();
+        for snapshot in snapshots {
+            let mut room = Room::new();
+            // carry on numbering where the saved history left off
+            let next_seq = snapshot.history.last().map_or(1, |msg| msg.seq + 1);
+            room.next_seq = AtomicU64::new(next_seq);
+            room.history = snapshot.history.into();
+            // a snapshot may have been saved with a longer history; keep the newest
+            let excess = room.history.len().saturating_sub(HISTORY_LEN);
+            room.history.drain(..excess);
+            room.topic = snapshot.topic;
+            // saved under another --default-room, perhaps; this one is never locked
+            if snapshot.name != self.default_room() {
+                room.password = snapshot.password;
+            }
+            write_guard.insert(snapshot.name, room);
+        }
+    }
+    fn count(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+    fn messages_sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+    // (name, users, messages) for every room, busiest first
+    fn get_existing(&self) -> Vec<(String, usize, u64)> {
+        let mut rooms = Vec::new();
+        for s in self.map.read().unwrap().iter() {
+            rooms.push((
+                s.0.clone(),
+                s.1.users.len(),
+                s.1.messages.load(Ordering::Relaxed),
+            ));
+        }
+        rooms.sort_by(|a, b| {
+            use std::cmp::Ordering::*;
+            match b.1.cmp(&a.1) {
+                Equal => a.0.cmp(&b.0),
+                ordering => ordering,
+            }
+        });
+        rooms
+    }
+}
+
+struct Invite {
+    // found again by channel, so the code follows renames and dies with the room
+    room_tx: Sender<ChatMessage>,
+    expires: Instant,
+}
+
+// single-use /invite codes, each mapped to the room it lets someone into
+#[derive(Clone)]
+struct Invites(Arc<Mutex<HashMap<String, Invite>>>);
+impl Invites {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+    fn create(&self, room_tx: &Sender<ChatMessage>) -> String {
+        let mut invites = self.0.lock().unwrap();
+        let now = Instant::now();
+        invites.retain(|_, invite| invite.expires > now);
+        loop {
+            let code = random_code(INVITE_CODE_LEN);
+            if let Entry::Vacant(entry) = invites.entry(code.clone()) {
+                entry.insert(Invite {
+                    room_tx: room_tx.clone(),
+                    expires: now + INVITE_TTL,
+                });
+                break code;
+            }
+        }
+    }
+    // consumes the code; None if it never existed, was used, or has expired
+    fn redeem(&self, code: &str) -> Option<Sender<ChatMessage>> {
+        let invite = self.0.lock().unwrap().remove(code)?;
+        (invite.expires > Instant::now()).then_some(invite.room_tx)
+    }
+}
+
+type NamePair = (String, String);
+
+// recent whispers between each pair of users, keyed by their name_keys in sorted order.
+// A conversation follows its participants through renames and is dropped when either
+// of them disconnects, so a later holder of the name never sees it.
+#[derive(Clone)]
+struct DirectMessages(Arc<Mutex<HashMap<NamePair, VecDeque<ChatMessage>>>>);
+impl DirectMessages {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+    fn key(a: &str, b: &str) -> NamePair {
+        let (a, b) = (name_key(a), name_key(b));
+        if a <= b { (a, b) } else { (b, a) }
+    }
+    fn record(&self, from: &str, to: &str, msg: ChatMessage) {
+        let mut conversations = self.0.lock().unwrap();
+        let history = conversations.entry(Self::key(from, to)).or_default();
+        if history.len() == DM_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(msg);
+    }
+    fn history(&self, a: &str, b: &str) -> Vec<ChatMessage> {
+        let conversations = self.0.lock().unwrap();
+        conversations
+            .get(&Self::key(a, b))
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    fn rename(&self, old_name: &str, new_name: &str) {
+        let (old_key, new_key) = (name_key(old_name), name_key(new_name));
+        if old_key == new_key {
+            return;
+        }
+        let mut conversations = self.0.lock().unwrap();
+        let keys = conversations
+            .keys()
+            .filter(|(a, b)| *a == old_key || *b == old_key)
+            .cloned()
+            .collect::<Vec<_>>();
+        for (a, b) in keys {
+            let history = conversations.remove(&(a.clone(), b.clone())).unwrap();
+            let other = if a == old_key { b } else { a };
+            // a whisper to oneself has `old_key` on both sides
+            let other = if other == old_key { new_name } else { &other };
+            conversations.insert(Self::key(new_name, other), history);
+        }
+    }
+    fn forget(&self, user_name: &str) {
+        let key = name_key(user_name);
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(a, b), _| *a != key && *b != key);
+    }
+}
+
+// a RateLimiter for each address posting over HTTP, as each WebSocket connection has one;
+// keyed by address rather than the sender name, which the client picks for itself
+#[derive(Clone)]
+struct HttpLimits(Arc<Mutex<HashMap<IpAddr, RateLimiter>>>);
+impl HttpLimits {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+    fn allow(&self, peer: IpAddr) -> bool {
+        let mut limits = self.0.lock().unwrap();
+        limits.retain(|_, limiter| !limiter.idle());
+        limits.entry(peer).or_insert_with(RateLimiter::new).allow()
+    }
+}
+
+struct Resume {
+    user_name: String,
+    room_name: String,
+    // that room's channel, to tell it apart from a room later created under the same name
+    room_tx: Sender<ChatMessage>,
+    expires: Instant,
+}
+
+// where each closed connection was, keyed by the token it was given on connect
+#[derive(Clone)]
+struct Resumes(Arc<Mutex<HashMap<String, Resume>>>);
+impl Resumes {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+    // called as a connection closes; the token only becomes redeemable from then on
+    fn store(
+        &self,
+        token: String,
+        user_name: &str,
+        room_name: &str,
+        room_tx: &Sender<ChatMessage>,
+    ) {
+        let mut resumes = self.0.lock().unwrap();
+        let now = Instant::now();
+        resumes.retain(|_, resume| resume.expires > now);
+        resumes.insert(
+            token,
+            Resume {
+                user_name: user_name.to_owned(),
+                room_name: room_name.to_owned(),
+                room_tx: room_tx.clone(),
+                expires: now + RESUME_TTL,
+            },
+        );
+    }
+    // consumes the token; returns the name and room it was left with
+    fn redeem(&self, token: &str) -> Option<Resume> {
+        let resume = self.0.lock().unwrap().remove(token)?;
+        (resume.expires > Instant::now()).then_some(resume)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AuditRecord {
+    room: String,
+    #[serde(flatten)]
+    message: ChatMessage,
+}
+
+// appends every message broadcast in any room to a file, one JSON object per line;
+// a background task does the writing so senders never wait on the disk
+#[derive(Clone)]
+struct AuditLog(mpsc::UnboundedSender<AuditRecord>);
+impl AuditLog {
+    async fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<AuditRecord>();
+        let path = path.to_owned();
+        tokio::spawn(async move {
+            let mut file = tokio::io::BufWriter::new(file);
+            while let Some(record) = rx.recv().await {
+                let mut line = serde_json::to_vec(&record).expect("audit records serialize");
+                line.push(b'\n');
+                let mut written = file.write_all(&line).await;
+                // flush once the queue is drained rather than after every line
+                if written.is_ok() && rx.is_empty() {
+                    written = file.flush().await;
+                }
+                if let Err(e) = written {
+                    error!(path = %path.display(), error = %e, "failed to write audit log");
+                }
+            }
+        });
+        Ok(AuditLog(tx))
+    }
+    fn record(&self, room_name: &str, msg: &ChatMessage) {
+        let _ = self.0.send(AuditRecord {
+            room: room_name.to_owned(),
+            message: msg.clone(),
+        });
+    }
+}
+
+fn random_code(len: usize) -> String {
+    std::iter::repeat_with(fastrand::alphanumeric)
+        .take(len)
+        .collect()
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Multi-room WebSocket chat server")]
+struct Args {
+    /// IP address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    addr: IpAddr,
+    /// TCP port to listen on
+    #[arg(long, default_value_t = 6142)]
+    port: u16,
+    /// Password that /admin takes to unlock admin commands such as /announce; none if unset
+    #[arg(long, value_name = "PASSWORD")]
+    admin_password: Option<String>,
+    /// PEM certificate chain; serves wss:// together with --key
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM private key for --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// File whose contents greet each new connection
+    #[arg(long, value_name = "PATH")]
+    welcome: Option<PathBuf>,
+    /// File of words, one per line, masked out of chat messages
+    #[arg(long, value_name = "PATH")]
+    word_filter: Option<PathBuf>,
+    /// JSON file rooms are saved to and restored from across restarts
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// File every broadcast message is appended to, as one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Seconds between periodic saves to --snapshot
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
+    /// Largest file, in bytes, that users may send each other
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_file_bytes: usize,
+    /// Seconds between the pings sent to each client to keep the connection alive
+    #[arg(long, value_name = "SECS", default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_interval: u64,
+    /// Heartbeat pings a client may leave unanswered in a row before it is disconnected
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    max_missed_pongs: u32,
+    /// Seconds a write to a client may block before the client is presumed dead and dropped
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    send_timeout: u64,
+    /// Seconds a released name stays reserved for the connection that gave it up (0 disables)
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    name_cooldown: u64,
+    /// Room every connection starts in, and is sent back to by /kick or when its room vanishes
+    #[arg(long, value_name = "ROOM", default_value = "main", value_parser = parse_room_name)]
+    default_room: String,
+    /// Most rooms that may exist at once, counting the default room; joins that would create another are refused
+    #[arg(long, value_name = "N")]
+    max_rooms: Option<usize>,
+    /// Rooms one connection may create per minute with /join (0 disables the limit)
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    room_creations: usize,
+    /// Most WebSocket connections served at once; further upgrades get 503
+    #[arg(long, default_value_t = 1000)]
+    max_connections: usize,
+}
+
+// settings fixed at startup, shared read-only by every connection
+struct Config {
+    // checked by /admin; rights go with the connection, not the name, so a user who takes
+    // an admin's name while they are away gains nothing
+    admin_password: Option<PasswordHash>,
+    welcome: String,
+    // lowercase; empty when no --word-filter was given
+    filtered_words: HashSet<String>,
+    snapshot: Option<PathBuf>,
+    max_file_bytes: usize,
+    heartbeat_interval: Duration,
+    max_missed_pongs: u32,
+    send_timeout: Duration,
+    room_creations: usize,
+}
+
+#[derive(Clone)]
+struct AppState {
+    rooms: Rooms,
+    names: Names,
+    invites: Invites,
+    resumes: Resumes,
+    direct_messages: DirectMessages,
+    http_limits: HttpLimits,
+    // every connect and disconnect on the server, for connections that turned on /presence
+    presence: Sender<ChatMessage>,
+    // cancelled once the shutdown grace period has elapsed
+    shutdown: CancellationToken,
+    // upgraded sockets outlive their HTTP request, so they are tracked separately
+    connections: TaskTracker,
+    // one permit per open connection, held for as long as its process() runs
+    connection_slots: Arc<Semaphore>,
+    config: Arc<Config>,
+    metrics: PrometheusHandle,
+    started: Instant,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // verbosity is controlled with RUST_LOG, e.g. RUST_LOG=chat_server=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let started = Instant::now();
+    let args = Args::parse();
+    let tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to load TLS certificate {} and key {}",
+                        cert.display(),
+                        key.display()
+                    )
+                })?,
+        ),
+        _ => None,
+    };
+    let welcome = match &args.welcome {
+        Some(path) => tokio::fs::read_to_string(path).await.unwrap_or_else(|e| {
+            warn!(path = %path.display(), error = %e, "failed to read welcome file, using the default");
+            DEFAULT_WELCOME_MSG.to_owned()
+        }),
+        None => DEFAULT_WELCOME_MSG.to_owned(),
+    };
+    let filtered_words = match &args.word_filter {
+        Some(path) => tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read word filter {}", path.display()))?
+            .lines()
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect(),
+        None => HashSet::new(),
+    };
+    let audit = match &args.audit_log {
+        Some(path) => Some(
+            AuditLog::open(path)
+                .await
+                .with_context(|| format!("failed to open audit log {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let rooms = Rooms::new(audit, args.max_rooms, &args.default_room);
+    if let Some(path) = &args.snapshot {
+        let snapshots = load_snapshot(path)
+            .await
+            .with_context(|| format!("failed to load snapshot {}", path.display()))?;
+        info!(path = %path.display(), rooms = snapshots.len(), "restored rooms");
+        rooms.restore(snapshots);
+    }
+    let addr = SocketAddr::new(args.addr, args.port);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {addr}"))?;
+    info!(%addr, tls = tls.is_some(), "chat server listening");
+    let metrics = PrometheusBuilder::new()
+        .install_recorder()
+        .context("failed to install metrics recorder")?;
+    let state = AppState {
+        rooms,
+        names: Names::new(Duration::from_secs(args.name_cooldown)),
+        invites: Invites::new(),
+        resumes: Resumes::new(),
+        direct_messages: DirectMessages::new(),
+        http_limits: HttpLimits::new(),
+        presence: broadcast::channel(PRESENCE_CAPACITY).0,
+        shutdown: CancellationToken::new(),
+        connections: TaskTracker::new(),
+        connection_slots: Arc::new(Semaphore::new(args.max_connections)),
+        config: Arc::new(Config {
+            admin_password: args.admin_password.as_deref().map(PasswordHash::new),
+            welcome,
+            filtered_words,
+            snapshot: args.snapshot,
+            max_file_bytes: args.max_file_bytes,
+            heartbeat_interval: Duration::from_secs(args.heartbeat_interval),
+            max_missed_pongs: args.max_missed_pongs,
+            send_timeout: Duration::from_secs(args.send_timeout),
+            room_creations: args.room_creations,
+        }),
+        metrics,
+        started,
+    };
+
+    if let Some(path) = state.config.snapshot.clone() {
+        let rooms = state.rooms.clone();
+        let mut ticker = time::interval(Duration::from_secs(args.snapshot_interval));
+        tokio::spawn(async move {
+            // the first tick completes at once, and there is nothing new to save yet
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = save_snapshot(&rooms, &path).await {
+                    warn!(path = %path.display(), error = %e, "failed to save snapshot");
+                }
+            }
+        });
+    }
+
+    let app = router(state.clone());
+
+    match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let state = state.clone();
+                async move {
+                    shutdown_signal(state).await;
+                    handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+                }
+            });
+            axum_server::from_tcp_rustls(listener.into_std()?, tls)?
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal(state.clone()))
+            .await?;
+        }
+    }
+
+    state.connections.close();
+    state.connections.wait().await;
+    Ok(())
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/ws", routing::any(ws_handler))
+        .route("/health", routing::get(health_handler))
+        .route("/metrics", routing::get(metrics_handler))
+        .route("/rooms", routing::get(rooms_handler))
+        .route("/rooms/{name}/users", routing::get(room_users_handler))
+        .route(
+            "/rooms/{name}/messages",
+            routing::post(post_message_handler),
+        )
+        .with_state(state)
+}
+
+async fn shutdown_signal(state: AppState) {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(error = %e, "failed to listen for ctrl-c");
+        // without a signal there is nothing to shut down on, so keep serving
+        std::future::pending::<()>().await;
+    }
+    info!("shutdown requested");
+    state.rooms.announce("Server is shutting down");
+    time::sleep(SHUTDOWN_GRACE).await;
+    // saved before connections close, since rooms are removed as their last user leaves
+    if let Some(path) = &state.config.snapshot {
+        match save_snapshot(&state.rooms, path).await {
+            Ok(()) => info!(path = %path.display(), "saved snapshot"),
+            Err(e) => error!(path = %path.display(), error = %e, "failed to save snapshot"),
+        }
+    }
+    state.shutdown.cancel();
+}
+
+// a missing file just means there is nothing to restore yet
+async fn load_snapshot(path: &Path) -> anyhow::Result<Vec<RoomSnapshot>> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// written beside `path` and renamed over it, so a crash mid-write keeps the old snapshot
+async fn save_snapshot(rooms: &Rooms, path: &Path) -> anyhow::Result<()> {
+    let json = serde_json::to_vec(&rooms.snapshot())?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, json).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    status: &'static str,
+    rooms: usize,
+    users: usize,
+}
+
+async fn health_handler(State(state): State<AppState>) -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        rooms: state.rooms.count(),
+        users: state.names.count(),
+    })
+}
+
+#[derive(serde::Serialize)]
+struct RoomSummary {
+    name: String,
+    users: usize,
+    messages: u64,
+}
+
+// the same listing as /rooms, busiest first, for dashboards that poll rather than connect
+async fn rooms_handler(State(state): State<AppState>) -> Json<Vec<RoomSummary>> {
+    Json(
+        state
+            .rooms
+            .get_existing()
+            .into_iter()
+            .map(|(name, users, messages)| RoomSummary {
+                name,
+                users,
+                messages,
+            })
+            .collect(),
+    )
+}
+
+#[derive(serde::Serialize)]
+struct RoomUsers {
+    room: String,
+    users: Vec<String>,
+}
+
+async fn room_users_handler(
+    State(state): State<AppState>,
+    UrlPath(room_name): UrlPath<String>,
+) -> Response {
+    match state.rooms.list_users(&room_name) {
+        Some(mut users) => {
+            users.sort();
+            Json(RoomUsers {
+                room: room_name,
+                users,
+            })
+            .into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "No such room.").into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PostMessage {
+    sender: String,
+    text: String,
+}
+
+// lets bots and integrations chat in an existing room without holding a WebSocket
+async fn post_message_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    UrlPath(room_name): UrlPath<String>,
+    Json(body): Json<PostMessage>,
+) -> Response {
+    if let Err(reason) = validate_name(&body.sender) {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    // a connected user's name would let anyone speak for them, as would one just released
+    if state.names.in_use(&body.sender) {
+        return (
+            StatusCode::CONFLICT,
+            "That name belongs to a connected user, or did until recently.",
+        )
+            .into_response();
+    }
+    if body.text.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Message text cannot be empty.").into_response();
+    }
+    if body.text.len() > MAX_MESSAGE_LEN {
+        return (StatusCode::PAYLOAD_TOO_LARGE, too_long_msg()).into_response();
+    }
+    if !state.http_limits.allow(peer.ip()) {
+        return (StatusCode::TOO_MANY_REQUESTS, TOO_FAST_MSG).into_response();
+    }
+    // held to what the sender could do over a WebSocket: no password, so no locked rooms
+    let tx = match state.rooms.channel_for(&room_name, &body.sender) {
+        Ok(tx) => tx,
+        Err(JoinError::Banned) => {
+            return (
+                StatusCode::FORBIDDEN,
+                "That sender is banned from this room.",
+            )
+                .into_response();
+        }
+        Err(JoinError::Locked) => {
+            return (StatusCode::FORBIDDEN, "That room has a password.").into_response();
+        }
+        Err(_) => return (StatusCode::NOT_FOUND, "No such room.").into_response(),
+    };
+    let sender = body.sender;
+    let text = mask_words(&body.text, &state.config.filtered_words);
+    let mut msg = ChatMessage::new(
+        MessageKind::Chat,
+        Some(&sender),
+        format!("{sender}: {text}"),
+    );
+    msg.mentions = find_mentions(
+        &text,
+        state.rooms.list_users(&room_name).unwrap_or_default(),
+    );
+    info!(%sender, %room_name, "message posted over HTTP");
+    match state.rooms.send(&room_name, &tx, msg) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        // the room emptied between the lookup and the send
+        Err(_) => (StatusCode::NOT_FOUND, "No such room.").into_response(),
+    }
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> String {
+    // room and user totals live in the shared state, so sample them at scrape time
+    gauge!("chat_active_rooms").set(state.rooms.count() as f64);
+    gauge!("chat_active_users").set(state.names.count() as f64);
+    state.metrics.render()
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    let Ok(slot) = state.connection_slots.clone().try_acquire_owned() else {
+        warn!("refusing connection, server is full");
+        counter!("chat_connections_refused_total").increment(1);
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is full").into_response();
+    };
+    ws.on_upgrade(move |socket| {
+        let connections = state.connections.clone();
+        connections.track_future(async move {
+            if let Err(e) = process(socket, state).await {
+                warn!(error = %e, "connection error");
+            }
+            drop(slot);
+        })
+    })
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("Name cannot be empty.".to_owned())
+    } else if name.chars().count() > MAX_NAME_LEN {
+        Err(format!("Name is too long (max {MAX_NAME_LEN} characters)."))
+    } else if name.chars().any(char::is_control) {
+        Err("Name cannot contain control characters.".to_owned())
+    } else if name.chars().all(char::is_whitespace) {
+        Err("Name cannot be only whitespace.".to_owned())
+    } else if is_reserved(name) {
+        Err("That name is reserved.".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+// room names are a single word, since /join takes the word after it as the name
+fn parse_room_name(name: &str) -> Result<String, String> {
+    validate_room_name(name).map(|()| name.to_owned())
+}
+
+fn validate_room_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("Room name cannot be empty.".to_owned())
+    } else if name.chars().count() > MAX_ROOM_NAME_LEN {
+        Err(format!(
+            "Room name is too long (max {MAX_ROOM_NAME_LEN} characters)."
+        ))
+    } else if name.chars().any(char::is_control) {
+        Err("Room name cannot contain control characters.".to_owned())
+    } else if name.chars().any(char::is_whitespace) {
+        Err("Room name cannot contain spaces.".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+// `users` whose name follows an @ in `text`, ignoring case; neither "@bobby" nor
+// "me@bob.com" mentions "bob"
+fn find_mentions(text: &str, users: Vec<String>) -> Vec<String> {
+    let text = text.to_lowercase();
+    users
+        .into_iter()
+        .filter(|user| {
+            let needle = format!("@{}", user.to_lowercase());
+            text.match_indices(&needle).any(|(i, _)| {
+                !text[..i].ends_with(|c: char| c.is_alphanumeric())
+                    && !text[i + needle.len()..].starts_with(|c: char| c.is_alphanumeric())
+            })
+        })
+        .collect()
+}
+
+// replaces every whole word of `text` that is in `words` (lowercase) with asterisks
+fn mask_words(text: &str, words: &HashSet<String>) -> String {
+    if words.is_empty() {
+        return text.to_owned();
+    }
+    let mut masked = String::with_capacity(text.len());
+    let push_word = |word: &str, masked: &mut String| {
+        if words.contains(&word.to_lowercase()) {
+            masked.extend(word.chars().map(|_| '*'));
+        } else {
+            masked.push_str(word);
+        }
+    };
+    let mut word_start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            word_start.get_or_insert(i);
+        } else {
+            if let Some(start) = word_start.take() {
+                push_word(&text[start..i], &mut masked);
+            }
+            masked.push(c);
+        }
+    }
+    if let Some(start) = word_start {
+        push_word(&text[start..], &mut masked);
+    }
+    masked
+}
+
+// e.g. "2h 13m"; seconds are only shown under an hour
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, mins) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {mins}m")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m")
+    } else {
+        format!("{mins}m {}s", secs % 60)
+    }
+}
+
+fn too_long_msg() -> String {
+    format!("Message too long (max {MAX_MESSAGE_LEN} bytes).")
+}
+
+// every command process() understands; an exhaustive match on this is the dispatcher
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Name,
+    Join,
+    Invite,
+    JoinCode,
+    Subscribe,
+    Unsubscribe,
+    Resume,
+    Msg,
+    DmHistory,
+    Me,
+    Edit,
+    Delete,
+    RenameRoom,
+    Topic,
+    Poll,
+    Vote,
+    PollResult,
+    Away,
+    Back,
+    Typing,
+    Kick,
+    Ban,
+    Transfer,
+    Users,
+    AllUsers,
+    Rooms,
+    Admin,
+    Announce,
+    Push,
+    Monitor,
+    Unmonitor,
+    Format,
+    Tz,
+    Echo,
+    Presence,
+    Silence,
+    Unsilence,
+    WhoAmI,
+    MyRoom,
+    Stats,
+    Names,
+    Ping,
+    Help,
+    Quit,
+}
+
+struct CommandSpec {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    usage: &'static str,
+    description: &'static str,
+    command: Command,
+}
+
+// the one list of commands: parsing and /help both read it, in this order
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "/name",
+        aliases: &["/rename-self"],
+        usage: "/name [NAME]",
+        description: "change user name to [NAME]",
+        command: Command::Name,
+    },
+    CommandSpec {
+        name: "/join",
+        aliases: &[],
+        usage: "/join [NAME] [PW]",
+        description: "change current room to [NAME]; [PW] locks a new room",
+        command: Command::Join,
+    },
+    CommandSpec {
+        name: "/invite",
+        aliases: &[],
+        usage: "/invite",
+        description: "get a one-time code that lets someone into this room",
+        command: Command::Invite,
+    },
+    CommandSpec {
+        name: "/join-code",
+        aliases: &[],
+        usage: "/join-code [CODE]",
+        description: "join the room an invite [CODE] is for, even if locked",
+        command: Command::JoinCode,
+    },
+    CommandSpec {
+        name: "/subscribe",
+        aliases: &[],
+        usage: "/subscribe [RM]",
+        description: "also see messages from room [RM] without leaving this one",
+        command: Command::Subscribe,
+    },
+    CommandSpec {
+        name: "/unsubscribe",
+        aliases: &[],
+        usage: "/unsubscribe [RM]",
+        description: "stop seeing messages from room [RM]",
+        command: Command::Unsubscribe,
+    },
+    CommandSpec {
+        name: "/resume",
+        aliases: &[],
+        usage: "/resume [TOKEN]",
+        description: "after reconnecting, get back the name and room of a lost connection",
+        command: Command::Resume,
+    },
+    CommandSpec {
+        name: "/msg",
+        aliases: &[],
+        usage: "/msg [NAME] [TEXT]",
+        description: "privately send [TEXT] to user [NAME]",
+        command: Command::Msg,
+    },
+    CommandSpec {
+        name: "/dm-history",
+        aliases: &[],
+        usage: "/dm-history [NAME]",
+        description: "see your recent whispers with [NAME]",
+        command: Command::DmHistory,
+    },
+    CommandSpec {
+        name: "/me",
+        aliases: &[],
+        usage: "/me [ACTION]",
+        description: "describe yourself doing [ACTION]",
+        command: Command::Me,
+    },
+    CommandSpec {
+        name: "/edit",
+        aliases: &[],
+        usage: "/edit [SEQ] [TEXT]",
+        description: "replace the text of your message number [SEQ]",
+        command: Command::Edit,
+    },
+    CommandSpec {
+        name: "/delete",
+        aliases: &[],
+        usage: "/delete [SEQ]",
+        description: "withdraw your message number [SEQ]",
+        command: Command::Delete,
+    },
+    CommandSpec {
+        name: "/renameroom",
+        aliases: &[],
+        usage: "/renameroom [NAME]",
+        description: "change room name to [NAME]",
+        command: Command::RenameRoom,
+    },
+    CommandSpec {
+        name: "/topic",
+        aliases: &[],
+        usage: "/topic [TEXT]",
+        description: "set the room topic, or show it when [TEXT] is empty",
+        command: Command::Topic,
+    },
+    CommandSpec {
+        name: "/poll",
+        aliases: &[],
+        usage: "/poll \"[Q]\" [A] [B]",
+        description: "ask the room question [Q], replacing any earlier poll",
+        command: Command::Poll,
+    },
+    CommandSpec {
+        name: "/vote",
+        aliases: &[],
+        usage: "/vote [N]",
+        description: "vote for option [N] of the room's poll; voting again changes it",
+        command: Command::Vote,
+    },
+    CommandSpec {
+        name: "/pollresult",
+        aliases: &[],
+        usage: "/pollresult",
+        description: "see the votes cast so far in the room's poll",
+        command: Command::PollResult,
+    },
+    CommandSpec {
+        name: "/away",
+        aliases: &[],
+        usage: "/away [MESSAGE]",
+        description: "mark yourself away, with an optional [MESSAGE]",
+        command: Command::Away,
+    },
+    CommandSpec {
+        name: "/back",
+        aliases: &[],
+        usage: "/back",
+        description: "clear your away status",
+        command: Command::Back,
+    },
+    CommandSpec {
+        name: "/typing",
+        aliases: &[],
+        usage: "/typing",
+        description: "tell the room you are typing",
+        command: Command::Typing,
+    },
+    CommandSpec {
+        name: "/kick",
+        aliases: &[],
+        usage: "/kick [NAME]",
+        description: "(room owner only) send [NAME] back to the default room",
+        command: Command::Kick,
+    },
+    CommandSpec {
+        name: "/ban",
+        aliases: &[],
+        usage: "/ban [NAME]",
+        description: "(room owner only) kick [NAME] and keep them out",
+        command: Command::Ban,
+    },
+    CommandSpec {
+        name: "/transfer",
+        aliases: &[],
+        usage: "/transfer [NAME]",
+        description: "(room owner only) make [NAME] the room owner",
+        command: Command::Transfer,
+    },
+    CommandSpec {
+        name: "/users",
+        aliases: &[],
+        usage: "/users",
+        description: "see current users in room",
+        command: Command::Users,
+    },
+    CommandSpec {
+        name: "/allusers",
+        aliases: &[],
+        usage: "/allusers",
+        description: "see all users in room",
+        command: Command::AllUsers,
+    },
+    CommandSpec {
+        name: "/rooms",
+        aliases: &[],
+        usage: "/rooms [TEXT] [PG]",
+        description: "see current rooms, a page [PG] at a time, filtered by [TEXT]",
+        command: Command::Rooms,
+    },
+    CommandSpec {
+        name: "/admin",
+        aliases: &[],
+        usage: "/admin [PW]",
+        description: "unlock admin commands on this connection",
+        command: Command::Admin,
+    },
+    CommandSpec {
+        name: "/announce",
+        aliases: &[],
+        usage: "/announce [TEXT]",
+        description: "(admins only) send [TEXT] to every room",
+        command: Command::Announce,
+    },
+    CommandSpec {
+        name: "/push",
+        aliases: &[],
+        usage: "/push [ROOM] [TEXT]",
+        description: "(admins only) send [TEXT] into [ROOM] without joining it",
+        command: Command::Push,
+    },
+    CommandSpec {
+        name: "/monitor",
+        aliases: &[],
+        usage: "/monitor",
+        description: "(admins only) see messages from every room",
+        command: Command::Monitor,
+    },
+    CommandSpec {
+        name: "/unmonitor",
+        aliases: &[],
+        usage: "/unmonitor",
+        description: "stop seeing messages from every room",
+        command: Command::Unmonitor,
+    },
+    CommandSpec {
+        name: "/format",
+        aliases: &[],
+        usage: "/format json|text",
+        description: "receive messages as JSON or as plain lines (the default)",
+        command: Command::Format,
+    },
+    CommandSpec {
+        name: "/tz",
+        aliases: &[],
+        usage: "/tz [+-HH:MM]",
+        description: "show plain-line timestamps at that offset from UTC",
+        command: Command::Tz,
+    },
+    CommandSpec {
+        name: "/echo",
+        aliases: &[],
+        usage: "/echo on|off",
+        description: "choose whether your own messages come back to you",
+        command: Command::Echo,
+    },
+    CommandSpec {
+        name: "/presence",
+        aliases: &[],
+        usage: "/presence on|off",
+        description: "choose whether to hear about every user connecting or disconnecting",
+        command: Command::Presence,
+    },
+    CommandSpec {
+        name: "/silence",
+        aliases: &[],
+        usage: "/silence [NAME]",
+        description: "stop seeing what user [NAME] says, on this connection only",
+        command: Command::Silence,
+    },
+    CommandSpec {
+        name: "/unsilence",
+        aliases: &[],
+        usage: "/unsilence [NAME]",
+        description: "see what user [NAME] says again",
+        command: Command::Unsilence,
+    },
+    CommandSpec {
+        name: "/whoami",
+        aliases: &[],
+        usage: "/whoami",
+        description: "see your name and current room",
+        command: Command::WhoAmI,
+    },
+    CommandSpec {
+        name: "/myroom",
+        aliases: &[],
+        usage: "/myroom",
+        description: "see the room you are in and the rooms you subscribe to",
+        command: Command::MyRoom,
+    },
+    CommandSpec {
+        name: "/stats",
+        aliases: &[],
+        usage: "/stats",
+        description: "see server uptime and totals",
+        command: Command::Stats,
+    },
+    CommandSpec {
+        name: "/names",
+        aliases: &[],
+        usage: "/names",
+        description: "see how many names new users may be given",
+        command: Command::Names,
+    },
+    CommandSpec {
+        name: "/ping",
+        aliases: &[],
+        usage: "/ping",
+        description: "see your latency to the server",
+        command: Command::Ping,
+    },
+    CommandSpec {
+        name: "/help",
+        aliases: &[],
+        usage: "/help [CMD]",
+        description: "list the commands, or explain [CMD]",
+        command: Command::Help,
+    },
+    CommandSpec {
+        name: "/quit",
+        aliases: &[],
+        usage: "/quit [REASON]",
+        description: "quits server, telling the room [REASON] if given",
+        command: Command::Quit,
+    },
+];
+
+impl Command {
+    // only an exact name or alias selects a command, so "/userscript" is plain chat
+    fn parse(word: &str) -> Option<Command> {
+        find_command(word).map(|spec| spec.command)
+    }
+}
+
+fn find_command(word: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == word || spec.aliases.contains(&word))
+}
+
+fn aliases_note(spec: &CommandSpec) -> String {
+    if spec.aliases.is_empty() {
+        String::new()
+    } else {
+        format!(" (also {})", spec.aliases.join(", "))
+    }
+}
+
+// sent on connect and by a bare /help
+static HELP_MSG: LazyLock<String> = LazyLock::new(|| {
+    let mut help = "Server commands".to_owned();
+    for spec in COMMANDS {
+        help.push_str(&format!(
+            "\n  {:<18} - {}{}",
+            spec.usage,
+            spec.description,
+            aliases_note(spec)
+        ));
+    }
+    help
+});
+
+// /help CMD, with or without the leading slash
+fn command_help(word: &str) -> Option<String> {
+    let word = format!("/{}", word.trim_start_matches('/'));
+    let spec = find_command(&word)?;
+    Some(format!(
+        "Usage: {}\n  {}{}",
+        spec.usage,
+        spec.description,
+        aliases_note(spec)
+    ))
+}
+
+// how a connection wants chat messages written, picked with /format
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// a connection's /format and /tz choices
+#[derive(Clone, Copy)]
+struct Output {
+    format: OutputFormat,
+    // text timestamps are shown at this offset from UTC; JSON always carries raw millis
+    offset: FixedOffset,
+}
+
+impl Output {
+    fn new() -> Self {
+        Output {
+            format: OutputFormat::Text,
+            offset: FixedOffset::east_opt(0).unwrap(),
+        }
+    }
+}
+
+// accepts exactly "+HH:MM" or "-HH:MM"
+fn parse_offset(text: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match text.as_bytes().first()? {
+        b'+' => (1, &text[1..]),
+        b'-' => (-1, &text[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    // unsigned, so a second sign as in "+-1:00" is refused
+    let hours = hours.parse::<u8>().ok()?;
+    let minutes = minutes.parse::<u8>().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (i32::from(hours) * 3600 + i32::from(minutes) * 60))
+}
+
+async fn send_chat(
+    socket: &mut TimedSocket,
+    msg: &ChatMessage,
+    output: Output,
+) -> anyhow::Result<()> {
+    socket.send(Message::Text(render(msg, output).into())).await
+}
+
+// `msg` as the connection asked for it with /format
+fn render(msg: &ChatMessage, output: Output) -> String {
+    // machine-readable JSON lets load tests parse timestamps reliably
+    if output.format == OutputFormat::Json {
+        return json_or_text(msg, output, serde_json::to_string(msg));
+    }
+    render_text(msg, output)
+}
+
+// the serialized `msg`, or its text form should serializing have failed
+fn json_or_text(msg: &ChatMessage, output: Output, json: serde_json::Result<String>) -> String {
+    match json {
+        Ok(json) => json,
+        // a client that asked for JSON may not parse the text that follows
+        Err(e) => {
+            warn!(
+                error = %e,
+                kind = ?msg.kind,
+                len = msg.message.len(),
+                "failed to serialize message, falling back to text"
+            );
+            render_text(msg, output)
+        }
+    }
+}
+
+// formatted text (timestamp is milliseconds)
+fn render_text(msg: &ChatMessage, output: Output) -> String {
+    let ts = msg.timestamp;
+    let secs = ts / 1000;
+    let nsecs = ((ts % 1000) * 1_000_000) as u32;
+    let dt = Utc
+        .timestamp_opt(secs, nsecs)
+        .single()
+        .unwrap()
+        .with_timezone(&output.offset);
+    let formatted_date = dt.format("%Y-%m-%d %H:%M:%S").to_string();
+    let millis = (ts % 1000).abs();
+    let zone = if output.offset.local_minus_utc() == 0 {
+        "UTC".to_owned()
+    } else {
+        dt.format("%:z").to_string()
+    };
+    let formatted_time = format!("{formatted_date}.{millis:03} {zone}");
+    format!("[{}] {}", formatted_time, msg.message)
+}
+
+// catches a new joiner up on the room's topic and recent history
+async fn send_backlog(
+    socket: &mut TimedSocket,
+    topic: Option<&str>,
+    history: &[ChatMessage],
+    output: Output,
+) -> anyhow::Result<()> {
+    if let Some(topic) = topic {
+        socket
+            .send(Message::Text(format!("Topic: {topic}").into()))
+            .await?;
+    }
+    for msg in history {
+        send_chat(socket, msg, output).await?;
+    }
+    Ok(())
+}
+
+async fn process(socket: WebSocket, state: AppState) -> anyhow::Result<()> {
+    let AppState {
+        rooms,
+        names: existing,
+        invites,
+        resumes,
+        direct_messages,
+        presence,
+        shutdown,
+        config,
+        started,
+        ..
+    } = state;
+    let mut socket = TimedSocket {
+        socket,
+        timeout: config.send_timeout,
+    };
+    let (inbox, mut whispers) = broadcast::channel(32);
+    let mut output = Output::new();
+    // whether this connection's own chat messages are sent back to it
+    let mut echo = true;
+    // set once /admin is given the --admin-password
+    let mut admin = false;
+    // name keys of the users this connection has /silenced
+    let mut silenced: HashSet<String> = HashSet::new();
+    // set while an admin has /monitor on
+    let mut monitor: Option<Monitor> = None;
+    // set while this connection has /presence on
+    let mut presence_feed: Option<Receiver<ChatMessage>> = None;
+    // rooms listened to with /subscribe, keyed by their current name; each sender is
+    // that room's channel, kept so the room can be found again after a rename
+    let mut subscriptions = StreamMap::new();
+    let mut subscribed: HashMap<String, Sender<ChatMessage>> = HashMap::new();
+    let (control, mut controls) = mpsc::unbounded_channel();
+    let (files, mut incoming_files) = mpsc::channel(FILE_QUEUE_LEN);
+    let handle = UserHandle {
+        inbox,
+        control,
+        files,
+    };
+    let mut user_name = existing.get_unique(&handle);
+    announce_presence(&presence, &user_name, true);
+    let default_room = rooms.default_room().to_owned();
+    let mut room_name = default_room.clone();
+    let joined = match rooms.join(&room_name, &user_name, Admission::Password(None)) {
+        Ok(joined) => joined,
+        Err(e) => {
+            let _ = socket.send(Message::Text(e.to_string().into())).await;
+            existing.remove(&user_name);
+            announce_presence(&presence, &user_name, false);
+            return Ok(());
+        }
+    };
+    let mut tx = joined.tx;
+    let mut rx = joined.rx;
+
+    let _ = rooms.notify_room(
+        &room_name,
+        &tx,
+        MessageKind::Join,
+        &user_name,
+        format!("{user_name} has joined the chat."),
+    );
+
+    let _ = socket
+        .send(Message::Text(config.welcome.clone().into()))
+        .await;
+    let _ = socket.send(Message::Text(HELP_MSG.as_str().into())).await;
+    let resume_token = random_code(RESUME_TOKEN_LEN);
+    let minutes = RESUME_TTL.as_secs() / 60;
+    let _ = socket
+        .send(Message::Text(
+            format!("If you get disconnected, send /resume {resume_token} within {minutes} minutes of reconnecting to get your name and room back.").into(),
+        ))
+        .await;
+    let _ = send_backlog(
+        &mut socket,
+        joined.topic.as_deref(),
+        &joined.history,
+        output,
+    )
+    .await;
+
+    let mut limiter = RateLimiter::new();
+    let mut creations = RoomCreations::new(config.room_creations);
+    let mut last_typing: Option<Instant> = None;
+    let connected_at = Instant::now();
+    let mut last_activity = connected_at;
+    let mut latency = Latency::new();
+    let mut unanswered = UnansweredPings::new(config.max_missed_pongs);
+    // given with /quit, and told to the room in place of the usual farewell
+    let mut quit_reason: Option<String> = None;
+
+    let mut heartbeat = time::interval(config.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    // main loop returns Result so `b!` can break with Err
+    info!(%user_name, %room_name, "connection opened");
+    gauge!("chat_active_connections").increment(1);
+
+    let result: anyhow::Result<()> = loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(msg) => b!(msg),
+                    None => break Ok(()), // client closed
+                };
+                last_activity = Instant::now();
+
+                let user_msg = match msg {
+                    Message::Text(t) => t,
+                    Message::Binary(frame) => {
+                        let Some((request, data)) = parse_file_frame(&frame) else {
+                            b!(socket.send(Message::Text("Malformed file frame.".into())).await);
+                            continue;
+                        };
+                        if data.len() > config.max_file_bytes {
+                            b!(socket.send(Message::Text(format!("File too large (max {} bytes).", config.max_file_bytes).into())).await);
+                            continue;
+                        }
+                        if let Err(reason) = validate_filename(&request.filename) {
+                            b!(socket.send(Message::Text(reason.into())).await);
+                            continue;
+                        }
+                        let words = request.to.split_ascii_whitespace().collect::<Vec<&str>>();
+                        let Some((_, target)) = existing.find_user(&words).filter(|(n, _)| *n == words.len()) else {
+                            b!(socket.send(Message::Text("No such user.".into())).await);
+                            continue;
+                        };
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let size = data.len();
+                        let transfer = FileTransfer { from: user_name.clone(), filename: request.filename.clone(), data };
+                        let reply = match target.handle.files.try_send(transfer) {
+                            Ok(()) => {
+                                info!(from = %user_name, to = %target.display, filename = %request.filename, size, "relayed file");
+                                format!("(file to {}: {}, {size} bytes)", target.display, request.filename)
+                            }
+                            Err(mpsc::error::TrySendError::Full(_)) => format!("{} is still receiving other files; try again shortly.", target.display),
+                            Err(mpsc::error::TrySendError::Closed(_)) => "No such user.".to_owned(),
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                        continue;
+                    }
+                    Message::Ping(_) => continue,
+                    Message::Pong(payload) => {
+                        unanswered.answered();
+                        // heartbeat pings carry their send time, in micros since connecting
+                        if let Ok(sent) = <[u8; 8]>::try_from(payload.as_ref()) {
+                            let sent = Duration::from_micros(u64::from_be_bytes(sent));
+                            latency.record(connected_at.elapsed().saturating_sub(sent));
+                        }
+                        continue;
+                    }
+                    Message::Close(_) => break Ok(()),
+                };
+
+                let command = user_msg.split_ascii_whitespace().next().unwrap_or_default();
+
+                match Command::parse(command) {
+                    Some(Command::Join) => {
+
+                        let mut itr = user_msg.split_ascii_whitespace();
+                        itr.next();
+                        let new_room = itr.next().unwrap_or_default().to_owned();
+                        let password = itr.collect::<Vec<&str>>().join(" ");
+                        let password = (!password.is_empty()).then_some(password.as_str());
+                        if new_room.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /join [ROOM] [PASSWORD]".into())).await);
+                            continue;
+                        }
+                        if let Err(reason) = validate_room_name(&new_room) {
+                            b!(socket.send(Message::Text(reason.into())).await);
+                            continue;
+                        }
+
+                        if new_room == room_name {
+                            b!(socket.send(Message::Text("You are already in this room.".into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+
+                        // a room created by someone else in the meantime is not held against this user
+                        if rooms.channel(&new_room).is_none() && !creations.allow() {
+                            b!(socket.send(Message::Text(CREATING_TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let joined = match rooms.change(&room_name, &new_room, &user_name, Admission::Password(password)) {
+                            Ok(joined) => joined,
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        };
+                        if joined.created {
+                            creations.record();
+                        }
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::Leave, &user_name, format!("{user_name} has left {room_name}.")));
+                        tx = joined.tx;
+                        rx = joined.rx;
+                        info!(%user_name, from = %room_name, to = %new_room, created = joined.created, "changed room");
+                        room_name = new_room;
+                        let notice = if joined.created { format!("You created room {room_name}.") } else { format!("You joined room {room_name}.") };
+                        b!(socket.send(Message::Text(notice.into())).await);
+                        b!(send_backlog(&mut socket, joined.topic.as_deref(), &joined.history, output).await);
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::Join, &user_name, format!("{user_name} has joined {room_name}.")));
+                    }
+                    Some(Command::Invite) => {
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let code = invites.create(&tx);
+                        let minutes = INVITE_TTL.as_secs() / 60;
+                        b!(socket.send(Message::Text(format!("Invite code for {room_name}: {code} (single use, valid for {minutes} minutes). Others can enter with /join-code {code}").into())).await);
+                    }
+                    Some(Command::JoinCode) => {
+                        let Some(code) = user_msg.split_ascii_whitespace().nth(1) else {
+                            b!(socket.send(Message::Text("Usage: /join-code [CODE]".into())).await);
+                            continue;
+                        };
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let Some(invite_tx) = invites.redeem(code) else {
+                            b!(socket.send(Message::Text("That invite code is invalid or has expired.".into())).await);
+                            continue;
+                        };
+                        let Some(new_room) = rooms.name_of(&invite_tx) else {
+                            b!(socket.send(Message::Text(JoinError::NoSuchRoom.to_string().into())).await);
+                            continue;
+                        };
+                        if new_room == room_name {
+                            b!(socket.send(Message::Text("You are already in this room.".into())).await);
+                            continue;
+                        }
+                        let joined = match rooms.change(&room_name, &new_room, &user_name, Admission::Invite(&invite_tx)) {
+                            Ok(joined) => joined,
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        };
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::Leave, &user_name, format!("{user_name} has left {room_name}.")));
+                        tx = joined.tx;
+                        rx = joined.rx;
+                        info!(%user_name, from = %room_name, to = %new_room, "changed room by invite");
+                        room_name = new_room;
+                        b!(send_backlog(&mut socket, joined.topic.as_deref(), &joined.history, output).await);
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::Join, &user_name, format!("{user_name} has joined {room_name}.")));
+                    }
+                    Some(Command::Resume) => {
+                        let Some(token) = user_msg.split_ascii_whitespace().nth(1) else {
+                            b!(socket.send(Message::Text("Usage: /resume [TOKEN]".into())).await);
+                            continue;
+                        };
+                        let Some(Resume { user_name: old_name, room_name: old_room, room_tx: old_tx, .. }) = resumes.redeem(token) else {
+                            b!(socket.send(Message::Text("That resume token is invalid or has expired.".into())).await);
+                            continue;
+                        };
+                        if old_name != user_name {
+                            if !existing.reclaim(&user_name, old_name.clone(), &handle) {
+                                b!(socket.send(Message::Text(format!("Could not resume: the name {old_name} has been taken.").into())).await);
+                                continue;
+                            }
+                            if let Err(e) = rooms.change_name(&room_name, &user_name, &old_name) {
+                                warn!(%user_name, %old_name, %room_name, error = %e, "rolling back resumed name");
+                                if !existing.rename(&old_name, user_name.clone(), &handle) {
+                                    user_name = old_name;
+                                }
+                                if let RoomError::RoomNotFound = e {
+                                    let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                }
+                                b!(socket.send(Message::Text("Could not resume: your room no longer exists.".into())).await);
+                                continue;
+                            }
+                            direct_messages.rename(&user_name, &old_name);
+                            b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &old_name, format!("{user_name} is now {old_name}")));
+                            user_name = old_name;
+                        }
+                        if old_room != room_name {
+                            // the room may have emptied and been removed once this user dropped out,
+                            // in which case it is re-created, or replaced by someone else's room
+                            match rooms.change(&room_name, &old_room, &user_name, Admission::Rejoin(&old_tx)) {
+                                Ok(joined) => {
+                                    b!(rooms.notify_room(&room_name, &tx, MessageKind::Leave, &user_name, format!("{user_name} has left {room_name}.")));
+                                    tx = joined.tx;
+                                    rx = joined.rx;
+                                    room_name = old_room;
+                                    b!(send_backlog(&mut socket, joined.topic.as_deref(), &joined.history, output).await);
+                                    b!(rooms.notify_room(&room_name, &tx, MessageKind::Join, &user_name, format!("{user_name} has joined {room_name}.")));
+                                }
+                                Err(e) => {
+                                    b!(socket.send(Message::Text(format!("Could not rejoin {old_room}: {e}").into())).await);
+                                }
+                            }
+                        }
+                        info!(%user_name, %room_name, "resumed session");
+                        b!(socket.send(Message::Text(format!("Resumed as {user_name} in {room_name}.").into())).await);
+                    }
+                    Some(Command::Name) => {
+                        let mut itr = user_msg.split_ascii_whitespace();
+                        itr.next();
+                        let new_name = itr.collect::<Vec<&str>>().join(" ");
+                        if let Err(reason) = validate_name(&new_name) {
+                            b!(socket.send(Message::Text(reason.into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        // claiming the name in `existing` is what guarantees uniqueness, so it
+                        // goes first and is undone if the room cannot take the new name
+                        let changed_name = existing.rename(&user_name, new_name.clone(), &handle);
+                        if changed_name {
+                            if let Err(e) = rooms.change_name(&room_name, &user_name, &new_name) {
+                                warn!(%user_name, %new_name, %room_name, error = %e, "rolling back name change");
+                                if !existing.rename(&new_name, user_name.clone(), &handle) {
+                                    // someone took the old name meanwhile; the new one is all we have
+                                    user_name = new_name;
+                                }
+                                if let RoomError::RoomNotFound = e {
+                                    let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                }
+                                b!(socket.send(Message::Text("Could not change your name: your room no longer exists.".into())).await);
+                                continue;
+                            }
+                            info!(old_name = %user_name, %new_name, %room_name, "changed name");
+                            counter!("chat_name_changes_total").increment(1);
+                            direct_messages.rename(&user_name, &new_name);
+                            b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &new_name, format!("{user_name} is now {new_name}")));
+                            if let Some(users) = rooms.list_users(&room_name) {
+                                b!(rooms.send(&room_name, &tx, ChatMessage::system(format!("Current names in room: {users:?}"))));
+                            }
+                            user_name = new_name;
+                        }
+                        else {
+                            b!(socket.send(Message::Text("Sorry, that name is taken.".into())).await);
+                        }
+                    }
+                    Some(Command::Msg) => {
+                        let words = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>();
+                        let Some((name_len, target)) = existing.find_user(&words) else {
+                            b!(socket.send(Message::Text("No such user.".into())).await);
+                            continue;
+                        };
+                        let text = words[name_len..].join(" ");
+                        if text.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /msg [NAME] [TEXT]".into())).await);
+                            continue;
+                        }
+                        if text.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        // the recipient may disconnect between lookup and send; treat that as missing too
+                        if target.handle.inbox.send(ChatMessage::new(MessageKind::Whisper, Some(&user_name), format!("(whisper from {user_name}): {text}"))).is_err() {
+                            b!(socket.send(Message::Text("No such user.".into())).await);
+                            continue;
+                        }
+                        direct_messages.record(&user_name, &target.display, ChatMessage::new(MessageKind::Whisper, Some(&user_name), format!("{user_name} -> {}: {text}", target.display)));
+                        b!(socket.send(Message::Text(format!("(whisper to {}): {text}", target.display).into())).await);
+                    }
+                    Some(Command::DmHistory) => {
+                        let other = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if other.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /dm-history [NAME]".into())).await);
+                            continue;
+                        }
+                        let history = direct_messages.history(&user_name, &other);
+                        if history.is_empty() {
+                            b!(socket.send(Message::Text(format!("No private messages with {other}.").into())).await);
+                            continue;
+                        }
+                        b!(socket.send(Message::Text(format!("Private messages with {other}:").into())).await);
+                        b!(send_backlog(&mut socket, None, &history, output).await);
+                    }
+                    Some(Command::Me) => {
+                        let action = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if action.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /me [ACTION]".into())).await);
+                        } else if action.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                        } else if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                        } else {
+                            let action = mask_words(&action, &config.filtered_words);
+                            let alone = rooms.list_users(&room_name).is_none_or(|users| users.len() <= 1);
+                            if rooms.send(&room_name, &tx, ChatMessage::new(MessageKind::Chat, Some(&user_name), format!("* {user_name} {action}"))).is_err() || alone {
+                                b!(socket.send(Message::Text(NOT_DELIVERED_MSG.into())).await);
+                            }
+                        }
+                    }
+                    Some(Command::Edit) => {
+                        let mut args = user_msg.split_ascii_whitespace().skip(1);
+                        let seq = args.next().and_then(|seq| seq.parse::<u64>().ok());
+                        let text = args.collect::<Vec<&str>>().join(" ");
+                        let Some(seq) = seq.filter(|_| !text.is_empty()) else {
+                            b!(socket.send(Message::Text("Usage: /edit [SEQ] [TEXT] (seq is shown with /format json)".into())).await);
+                            continue;
+                        };
+                        if text.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let text = mask_words(&text, &config.filtered_words);
+                        match rooms.edit(&room_name, &tx, &user_name, seq, format!("{user_name}: {text} (edited)")) {
+                            // relayed as is: the edit is already applied to history
+                            Ok(event) => rooms.relay(&room_name, &tx, event),
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                            }
+                            Err(e) => b!(socket.send(Message::Text(e.to_string().into())).await),
+                        }
+                    }
+                    Some(Command::Delete) => {
+                        let Some(seq) = user_msg.split_ascii_whitespace().nth(1).and_then(|seq| seq.parse::<u64>().ok()) else {
+                            b!(socket.send(Message::Text("Usage: /delete [SEQ] (seq is shown with /format json)".into())).await);
+                            continue;
+                        };
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        match rooms.delete(&room_name, &tx, &user_name, seq) {
+                            Ok(event) => rooms.relay(&room_name, &tx, event),
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                            }
+                            Err(e) => b!(socket.send(Message::Text(e.to_string().into())).await),
+                        }
+                    }
+                    Some(Command::Admin) => {
+                        let password = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        let Some(hash) = &config.admin_password else {
+                            b!(socket.send(Message::Text("This server has no admin password.".into())).await);
+                            continue;
+                        };
+                        // counted against the limit so the password cannot be guessed quickly
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let reply = if hash.matches(&password) {
+                            admin = true;
+                            info!(%user_name, "admin rights granted");
+                            "You are now an admin on this connection."
+                        } else {
+                            warn!(%user_name, "wrong admin password");
+                            "Incorrect admin password."
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Announce) => {
+                        if !admin {
+                            b!(socket.send(Message::Text("Only admins may use /announce.".into())).await);
+                            continue;
+                        }
+                        let text = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if text.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /announce [TEXT]".into())).await);
+                            continue;
+                        }
+                        if text.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        rooms.announce(&format!("[Announcement] {text}"));
+                    }
+                    Some(Command::Push) => {
+                        if !admin {
+                            b!(socket.send(Message::Text("Only admins may use /push.".into())).await);
+                            continue;
+                        }
+                        let mut args = user_msg.split_ascii_whitespace().skip(1);
+                        let target = args.next().unwrap_or_default().to_owned();
+                        let text = args.collect::<Vec<&str>>().join(" ");
+                        if text.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /push [ROOM] [TEXT]".into())).await);
+                            continue;
+                        }
+                        if text.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let Some(target_tx) = rooms.channel(&target) else {
+                            b!(socket.send(Message::Text(format!("There is no room named {target}.").into())).await);
+                            continue;
+                        };
+                        let text = mask_words(&text, &config.filtered_words);
+                        let mut msg = ChatMessage::new(MessageKind::Chat, Some(&user_name), format!("{user_name} (from {room_name}): {text}"));
+                        let members = rooms.list_users(&target).unwrap_or_default();
+                        // a room restored from a snapshot may have nobody in it yet
+                        let empty = members.is_empty();
+                        msg.mentions = find_mentions(&text, members);
+                        let reply = match rooms.send(&target, &target_tx, msg) {
+                            Ok(_) if !empty => format!("Pushed to {target}."),
+                            _ => NOT_DELIVERED_MSG.to_owned(),
+                        };
+                        info!(%user_name, %target, "pushed message to room");
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Monitor) => {
+                        if !admin {
+                            b!(socket.send(Message::Text("Only admins may use /monitor.".into())).await);
+                            continue;
+                        }
+                        let monitor = monitor.get_or_insert_with(Monitor::new);
+                        monitor.sync(&rooms);
+                        let every = config.heartbeat_interval.as_secs();
+                        b!(socket.send(Message::Text(format!("Monitoring all {} rooms; rooms created later are added within {every}s.", monitor.channels.len()).into())).await);
+                    }
+                    Some(Command::Unmonitor) => {
+                        let reply = if monitor.take().is_some() { "Stopped monitoring rooms." } else { "You are not monitoring rooms." };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::AllUsers) => {
+                        let users_str = format!("All users: {:?}", existing.get_existing());
+                        b!(socket.send(Message::Text(users_str.into())).await);
+                    }
+                    Some(Command::Away) => {
+                        let reason = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if reason.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let notice = if reason.is_empty() {
+                            format!("{user_name} is away.")
+                        } else {
+                            format!("{user_name} is away: {reason}")
+                        };
+                        match rooms.set_away(&room_name, &user_name, Some(reason)) {
+                            Ok(()) => {}
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                continue;
+                            }
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        }
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, notice));
+                    }
+                    Some(Command::Back) => {
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        match rooms.set_away(&room_name, &user_name, None) {
+                            Ok(()) => {}
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                continue;
+                            }
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        }
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, format!("{user_name} is back.")));
+                    }
+                    // relayed straight to the room channel: typing notices are not numbered or kept in history
+                    Some(Command::Typing) => {
+                        if last_typing.is_some_and(|at| at.elapsed() < TYPING_DEBOUNCE) {
+                            continue;
+                        }
+                        last_typing = Some(Instant::now());
+                        let notice = ChatMessage::new(MessageKind::Typing, Some(&user_name), format!("{user_name} is typing..."));
+                        // nobody else listening is not worth telling the sender about
+                        let _ = tx.send(notice);
+                    }
+                    Some(Command::Topic) => {
+                        let topic = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if topic.is_empty() {
+                            let reply = match rooms.topic(&room_name) {
+                                Some(topic) => format!("Topic: {topic}"),
+                                None => "No topic is set.".to_owned(),
+                            };
+                            b!(socket.send(Message::Text(reply.into())).await);
+                        } else if topic.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                        } else if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                        } else if let Err(e) = rooms.set_topic(&room_name, topic.clone()) {
+                            if let RoomError::RoomNotFound = e {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                            } else {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                            }
+                        } else {
+                            b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, format!("Topic changed to: {topic}")));
+                        }
+                    }
+                    Some(Command::Poll) => {
+                        let args = user_msg.split_once(char::is_whitespace).map_or("", |(_, args)| args);
+                        let Some((question, options)) = parse_poll(args) else {
+                            b!(socket.send(Message::Text(format!("Usage: /poll \"QUESTION\" OPTION OPTION ... (2 to {MAX_POLL_OPTIONS} options)").into())).await);
+                            continue;
+                        };
+                        if user_msg.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let question = mask_words(&question, &config.filtered_words);
+                        let options = options.iter().map(|option| mask_words(option, &config.filtered_words)).collect::<Vec<_>>();
+                        let listing = options.iter().enumerate().map(|(i, option)| format!("  {}. {option}", i + 1)).collect::<Vec<_>>().join("\n");
+                        match rooms.start_poll(&room_name, &tx, question.clone(), options) {
+                            Ok(()) => {}
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                continue;
+                            }
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        }
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, format!("{user_name} started a poll: {question}\n{listing}\nVote with /vote [N].")));
+                    }
+                    Some(Command::Vote) => {
+                        let Some(choice) = user_msg.split_ascii_whitespace().nth(1).and_then(|choice| choice.parse::<usize>().ok()) else {
+                            b!(socket.send(Message::Text("Usage: /vote [N]".into())).await);
+                            continue;
+                        };
+                        let reply = match rooms.vote(&room_name, &tx, &user_name, choice) {
+                            Ok(option) => format!("You voted for {option}."),
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                ROOM_GONE_MSG.to_owned()
+                            }
+                            Err(e) => e.to_string(),
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::PollResult) => {
+                        let reply = rooms.poll_result(&room_name).unwrap_or_else(|| "There is no poll in this room.".to_owned());
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Users) => {
+                        let users_str = match rooms.describe_users(&room_name) {
+                            Some(users) => format!("Users in current room: {users:?}"),
+                            None => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                ROOM_GONE_MSG.to_owned()
+                            }
+                        };
+                        b!(socket.send(Message::Text(users_str.into())).await);
+                    }
+                    Some(Command::Rooms) => {
+                        // a trailing number picks the page; anything before it filters by name
+                        let mut args = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>();
+                        let requested = match args.last().and_then(|page| page.parse::<usize>().ok()) {
+                            Some(page) => {
+                                args.pop();
+                                page
+                            }
+                            None => 1,
+                        };
+                        let filter = args.join(" ").to_lowercase();
+                        let existing_rooms = rooms
+                            .get_existing()
+                            .into_iter()
+                            .filter(|(name, _, _)| name.to_lowercase().contains(&filter))
+                            .collect::<Vec<_>>();
+                        if existing_rooms.is_empty() {
+                            b!(socket.send(Message::Text(format!("No rooms match \"{filter}\".").into())).await);
+                            continue;
+                        }
+                        let pages = existing_rooms.len().div_ceil(ROOMS_PAGE_SIZE).max(1);
+                        let page = requested.clamp(1, pages);
+                        if page != requested {
+                            b!(socket.send(Message::Text(format!("There is no page {requested}; showing page {page}.").into())).await);
+                        }
+                        let rooms_list = existing_rooms
+                            .into_iter()
+                            .skip((page - 1) * ROOMS_PAGE_SIZE)
+                            .take(ROOMS_PAGE_SIZE)
+                            .map(|(name, users, messages)| format!("{name} ({users} users, {messages} msgs)"))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        let rooms_str = format!("Current rooms: {rooms_list} (page {page}/{pages})");
+                        b!(socket.send(Message::Text(rooms_str.into())).await);
+                    }
+                    Some(Command::RenameRoom) => {
+                        let mut itr = user_msg.split_ascii_whitespace();
+                        itr.next();
+                        let new_room_name = itr.collect::<Vec<&str>>().join(" ");
+                        if new_room_name.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /renameroom [NAME]".into())).await);
+                            continue;
+                        }
+                        if let Err(reason) = validate_room_name(&new_room_name) {
+                            b!(socket.send(Message::Text(reason.into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+
+                        match rooms.change_room_name(&room_name, &new_room_name) {
+                            Ok(()) => {}
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                continue;
+                            }
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        }
+                        info!(%user_name, old_room = %room_name, new_room = %new_room_name, "renamed room");
+                        counter!("chat_room_renames_total").increment(1);
+                        for member in rooms.list_users(&new_room_name).unwrap_or_default() {
+                            if member != user_name && let Some(member) = existing.handle(&member) {
+                                let _ = member.control.send(Control::RoomRenamed { from: room_name.clone(), to: new_room_name.clone() });
+                            }
+                        }
+                        let notice = format!("Room {room_name} has been renamed to {new_room_name}.");
+                        room_name = new_room_name;
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, notice));
+                    }
+                    Some(Command::Kick | Command::Ban) => {
+                        let target = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if rooms.owner(&room_name).as_deref() != Some(user_name.as_str()) {
+                            b!(socket.send(Message::Text(format!("Only the room owner may use {command}.").into())).await);
+                            continue;
+                        }
+                        if target.is_empty() {
+                            b!(socket.send(Message::Text(format!("Usage: {command} [NAME]").into())).await);
+                            continue;
+                        }
+                        if name_key(&target) == name_key(&user_name) {
+                            b!(socket.send(Message::Text(format!("You cannot {} yourself.", &command[1..]).into())).await);
+                            continue;
+                        }
+                        // a ban may name someone absent, and is kept and announced as given
+                        if let Err(reason) = validate_name(&target) {
+                            b!(socket.send(Message::Text(reason.into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let member = rooms.find_member(&room_name, &target);
+                        if command == "/ban" {
+                            // absent users can be banned too, so they cannot come back later
+                            match rooms.ban(&room_name, &target) {
+                                Ok(()) => {}
+                                Err(RoomError::RoomNotFound) => {
+                                    let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                    b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    b!(socket.send(Message::Text(e.to_string().into())).await);
+                                    continue;
+                                }
+                            }
+                        } else if member.is_none() {
+                            b!(socket.send(Message::Text("No such user in this room.".into())).await);
+                            continue;
+                        }
+                        if let Some(handle) = member.as_deref().and_then(|member| existing.handle(member)) {
+                            let _ = handle.control.send(Control::Kick { room: room_name.clone() });
+                        }
+                        let target = member.unwrap_or(target);
+                        let action = if command == "/ban" { "banned" } else { "kicked" };
+                        info!(%user_name, %target, %room_name, action, "removed user from room");
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, format!("{target} was {action} by {user_name}.")));
+                    }
+                    Some(Command::Transfer) => {
+                        let target = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if target.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /transfer [NAME]".into())).await);
+                            continue;
+                        }
+                        if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                            continue;
+                        }
+                        let target = match rooms.transfer(&room_name, &user_name, &target) {
+                            Ok(target) => target,
+                            Err(RoomError::RoomNotFound) => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                b!(socket.send(Message::Text(ROOM_GONE_MSG.into())).await);
+                                continue;
+                            }
+                            Err(e) => {
+                                b!(socket.send(Message::Text(e.to_string().into())).await);
+                                continue;
+                            }
+                        };
+                        info!(%user_name, %target, %room_name, "transferred room ownership");
+                        b!(rooms.notify_room(&room_name, &tx, MessageKind::System, &user_name, format!("{user_name} handed ownership of {room_name} to {target}.")));
+                    }
+                    Some(Command::Format) => {
+                        let choice = user_msg.split_ascii_whitespace().nth(1);
+                        match choice {
+                            Some("json") => output.format = OutputFormat::Json,
+                            Some("text") => output.format = OutputFormat::Text,
+                            _ => {
+                                b!(socket.send(Message::Text("Usage: /format json|text".into())).await);
+                                continue;
+                            }
+                        }
+                        b!(socket.send(Message::Text(format!("Messages will now be sent as {}.", choice.unwrap_or_default()).into())).await);
+                    }
+                    Some(Command::Tz) => {
+                        let Some(offset) = user_msg.split_ascii_whitespace().nth(1).and_then(parse_offset) else {
+                            b!(socket.send(Message::Text("Usage: /tz +HH:MM|-HH:MM (e.g. /tz -05:00, or /tz +00:00 for UTC)".into())).await);
+                            continue;
+                        };
+                        output.offset = offset;
+                        b!(socket.send(Message::Text(format!("Timestamps will now be shown at UTC{offset}.").into())).await);
+                    }
+                    Some(Command::Echo) => {
+                        match user_msg.split_ascii_whitespace().nth(1) {
+                            Some("on") => echo = true,
+                            Some("off") => echo = false,
+                            _ => {
+                                b!(socket.send(Message::Text("Usage: /echo on|off".into())).await);
+                                continue;
+                            }
+                        }
+                        let reply = if echo { "Your messages will be echoed back to you." } else { "Your messages will no longer be echoed back to you." };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Presence) => {
+                        match user_msg.split_ascii_whitespace().nth(1) {
+                            Some("on") => {
+                                if presence_feed.is_none() {
+                                    presence_feed = Some(presence.subscribe());
+                                }
+                            }
+                            Some("off") => presence_feed = None,
+                            _ => {
+                                b!(socket.send(Message::Text("Usage: /presence on|off".into())).await);
+                                continue;
+                            }
+                        }
+                        let reply = if presence_feed.is_some() { "You will be told whenever a user connects or disconnects." } else { "You will no longer be told when users connect or disconnect." };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Silence) => {
+                        let target = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if target.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /silence [NAME]".into())).await);
+                            continue;
+                        }
+                        let reply = if name_key(&target) == name_key(&user_name) {
+                            "You cannot silence yourself.".to_owned()
+                        } else if silenced.insert(name_key(&target)) {
+                            format!("You will no longer see messages from {target}.")
+                        } else {
+                            format!("{target} is already silenced.")
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Unsilence) => {
+                        let target = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if target.is_empty() {
+                            b!(socket.send(Message::Text("Usage: /unsilence [NAME]".into())).await);
+                            continue;
+                        }
+                        let reply = if silenced.remove(&name_key(&target)) {
+                            format!("You will see messages from {target} again.")
+                        } else {
+                            format!("{target} is not silenced.")
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Subscribe) => {
+                        let Some(target) = user_msg.split_ascii_whitespace().nth(1) else {
+                            b!(socket.send(Message::Text("Usage: /subscribe [ROOM]".into())).await);
+                            continue;
+                        };
+                        let reply = if target == room_name {
+                            "You are already in this room.".to_owned()
+                        } else if subscribed.contains_key(target) {
+                            format!("You are already subscribed to {target}.")
+                        } else if subscribed.len() >= MAX_SUBSCRIPTIONS {
+                            format!("You can subscribe to at most {MAX_SUBSCRIPTIONS} rooms.")
+                        } else {
+                            match rooms.subscribe(target, &user_name) {
+                                Ok((room_tx, room_rx)) => {
+                                    subscriptions.insert(target.to_owned(), BroadcastStream::new(room_rx));
+                                    subscribed.insert(target.to_owned(), room_tx);
+                                    info!(%user_name, room = %target, "subscribed to room");
+                                    format!("Subscribed to {target}; its messages will start with [{target}].")
+                                }
+                                Err(JoinError::NoSuchRoom) => format!("There is no room named {target}."),
+                                Err(e) => e.to_string(),
+                            }
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Unsubscribe) => {
+                        let Some(target) = user_msg.split_ascii_whitespace().nth(1) else {
+                            b!(socket.send(Message::Text("Usage: /unsubscribe [ROOM]".into())).await);
+                            continue;
+                        };
+                        let reply = if subscribed.remove(target).is_some() {
+                            subscriptions.remove(target);
+                            format!("Unsubscribed from {target}.")
+                        } else {
+                            format!("You are not subscribed to {target}.")
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::WhoAmI) => {
+                        b!(socket.send(Message::Text(format!("You are {user_name} in {room_name}.").into())).await);
+                    }
+                    // a connection is a member of only one room; /join moves it, /subscribe only listens
+                    Some(Command::MyRoom) => {
+                        let reply = match rooms.list_users(&room_name) {
+                            Some(users) if !subscribed.is_empty() => {
+                                let mut others = subscribed.keys().cloned().collect::<Vec<_>>();
+                                others.sort();
+                                format!("You are in {room_name} ({} online), and subscribed to {}.", users.len(), others.join(", "))
+                            }
+                            Some(users) => format!("You are in {room_name} ({} online).", users.len()),
+                            None => {
+                                let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                                ROOM_GONE_MSG.to_owned()
+                            }
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Stats) => {
+                        let stats = format!(
+                            "Uptime: {}, rooms: {}, users: {}, messages: {}",
+                            format_uptime(started.elapsed()),
+                            rooms.count(),
+                            existing.count(),
+                            rooms.messages_sent()
+                        );
+                        b!(socket.send(Message::Text(stats.into())).await);
+                    }
+                    Some(Command::Names) => {
+                        let reply = format!("New users are named after one of {} famous people.", people_count());
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Ping) => {
+                        let reply = match (latency.last(), latency.average()) {
+                            (Some(last), Some(average)) => format!(
+                                "Latency: {:.1} ms (recent average {:.1} ms)",
+                                last.as_secs_f64() * 1000.0,
+                                average.as_secs_f64() * 1000.0
+                            ),
+                            _ => "No latency measured yet.".to_owned(),
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Help) => {
+                        let reply = match user_msg.split_ascii_whitespace().nth(1) {
+                            Some(word) => command_help(word).unwrap_or_else(|| format!("There is no /{} command; /help lists them all.", word.trim_start_matches('/'))),
+                            None => HELP_MSG.clone(),
+                        };
+                        b!(socket.send(Message::Text(reply.into())).await);
+                    }
+                    Some(Command::Quit) => {
+                        let reason = user_msg.split_ascii_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+                        if reason.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                            continue;
+                        }
+                        if !reason.is_empty() {
+                            quit_reason = Some(mask_words(&reason, &config.filtered_words));
+                        }
+                        break Ok(());
+                    }
+                    None => {
+                        if user_msg.len() > MAX_MESSAGE_LEN {
+                            b!(socket.send(Message::Text(too_long_msg().into())).await);
+                        }
+                        else if !limiter.allow() {
+                            b!(socket.send(Message::Text(TOO_FAST_MSG.into())).await);
+                        }
+                        else {
+                            let user_msg = mask_words(&user_msg, &config.filtered_words);
+                            let mut msg = ChatMessage::new(MessageKind::Chat, Some(&user_name), format!("{user_name}: {user_msg}"));
+                            let members = rooms.list_users(&room_name).unwrap_or_default();
+                            // the sender's own receiver keeps the send from failing, so being alone
+                            // in the room is what leaves a message undelivered; either way that is
+                            // the sender's business rather than a reason to drop their connection
+                            let alone = members.len() <= 1;
+                            msg.mentions = find_mentions(&user_msg, members);
+                            if rooms.send(&room_name, &tx, msg).is_err() || alone {
+                                b!(socket.send(Message::Text(NOT_DELIVERED_MSG.into())).await);
+                            }
+                        }
+                    }
+                }
+            },
+
+            peer_msg = rx.recv() => match peer_msg {
+                // the sender already knows they are typing
+                Ok(peer_msg) if peer_msg.kind == MessageKind::Typing && peer_msg.sender.as_deref() == Some(user_name.as_str()) => {}
+                Ok(peer_msg) if !echo && peer_msg.kind == MessageKind::Chat && peer_msg.sender.as_deref() == Some(user_name.as_str()) => {}
+                Ok(peer_msg) if is_silenced(&silenced, &peer_msg) => {}
+                Ok(peer_msg) => b!(send_chat(&mut socket, &peer_msg, output).await),
+                // a slow client falls behind the channel; skip ahead rather than drop it
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(%user_name, %room_name, missed, "room receiver lagged");
+                    b!(socket.send(Message::Text(format!("You missed {missed} messages.").into())).await);
+                }
+                Err(e @ RecvError::Closed) => break Err(e.into()),
+            },
+            Some((label, sub_msg)) = subscriptions.next(), if !subscriptions.is_empty() => {
+                let room_tx = subscribed[&label].clone();
+                // once this user /joins a room they are subscribed to, it also arrives on `rx`
+                if room_tx.same_channel(&tx) {
+                    continue;
+                }
+                let label = match rooms.name_of(&room_tx) {
+                    Some(current) if current != label => {
+                        if let Some(stream) = subscriptions.remove(&label) {
+                            subscriptions.insert(current.clone(), stream);
+                        }
+                        subscribed.remove(&label);
+                        subscribed.insert(current.clone(), room_tx);
+                        current
+                    }
+                    _ => label,
+                };
+                match sub_msg {
+                    Ok(sub_msg) if sub_msg.kind == MessageKind::Typing => {}
+                    Ok(sub_msg) if is_silenced(&silenced, &sub_msg) => {}
+                    Ok(mut sub_msg) => {
+                        sub_msg.message = format!("[{label}] {}", sub_msg.message);
+                        b!(send_chat(&mut socket, &sub_msg, output).await);
+                    }
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        warn!(%user_name, room = %label, missed, "subscription lagged");
+                        b!(socket.send(Message::Text(format!("You missed {missed} messages from {label}.").into())).await);
+                    }
+                }
+            },
+            Some((id, monitored)) = async { monitor.as_mut().unwrap().streams.next().await }, if monitor.as_ref().is_some_and(|monitor| !monitor.streams.is_empty()) => {
+                let room_tx = monitor.as_ref().unwrap().channels[&id].clone();
+                // the room this user is in, or subscribes to, already reaches them
+                if room_tx.same_channel(&tx) || subscribed.values().any(|sub_tx| sub_tx.same_channel(&room_tx)) {
+                    continue;
+                }
+                // deleted rooms are dropped on the next sync
+                let Some(label) = rooms.name_of(&room_tx) else {
+                    continue;
+                };
+                match monitored {
+                    Ok(monitored) if monitored.kind == MessageKind::Typing => {}
+                    Ok(monitored) if is_silenced(&silenced, &monitored) => {}
+                    Ok(mut monitored) => {
+                        monitored.message = format!("[{label}] {}", monitored.message);
+                        b!(send_chat(&mut socket, &monitored, output).await);
+                    }
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        warn!(%user_name, room = %label, missed, "monitor lagged");
+                        b!(socket.send(Message::Text(format!("You missed {missed} messages from {label}.").into())).await);
+                    }
+                }
+            },
+            update = async { presence_feed.as_mut().unwrap().recv().await }, if presence_feed.is_some() => match update {
+                Ok(update) => b!(send_chat(&mut socket, &update, output).await),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(%user_name, missed, "presence feed lagged");
+                    b!(socket.send(Message::Text(format!("You missed {missed} presence updates.").into())).await);
+                }
+                // AppState holds the sender for as long as the server runs
+                Err(e @ RecvError::Closed) => break Err(e.into()),
+            },
+            whisper = whispers.recv() => match whisper {
+                Ok(whisper) if is_silenced(&silenced, &whisper) => {}
+                Ok(whisper) => b!(send_chat(&mut socket, &whisper, output).await),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(%user_name, missed, "whisper inbox lagged");
+                    b!(socket.send(Message::Text(format!("You missed {missed} whispers.").into())).await);
+                }
+                Err(e @ RecvError::Closed) => break Err(e.into()),
+            },
+            Some(file) = incoming_files.recv() => {
+                let notice = FileNotice { from: &file.from, filename: &file.filename };
+                // names are validated before files are queued, so this is not expected
+                let Some(frame) = encode_file_frame(&notice, &file.data) else {
+                    warn!(%user_name, from = %file.from, "file header too long to relay");
+                    continue;
+                };
+                b!(socket.send(Message::Text(format!("(file from {}: {}, {} bytes)", file.from, file.filename, file.data.len()).into())).await);
+                b!(socket.send(Message::Binary(frame)).await);
+            },
+            // `handle` keeps a sender alive, so this never yields None
+            Some(control) = controls.recv() => match control {
+                // either may land after this user already moved on
+                Control::Kick { ref room } | Control::RoomGone { ref room } if *room != room_name => {}
+                // /users and /myroom report a room as gone on a name lookup, which a rename can fool
+                Control::RoomGone { .. } if rooms.room_of(&tx, &user_name).is_some() => {}
+                Control::Kick { .. } | Control::RoomGone { .. } => {
+                    let gone = matches!(control, Control::RoomGone { .. });
+                    let (notice, joined) = if gone {
+                        // not a member any more, so there is nothing to leave; a room
+                        // now under the same name belongs to someone else
+                        warn!(%user_name, %room_name, %default_room, "room vanished, rejoining default room");
+                        (format!("Room {room_name} no longer exists, so you have been moved to {default_room}."), rooms.join(&default_room, &user_name, Admission::Password(None)))
+                    } else {
+                        (format!("You have been removed from {room_name}."), rooms.change(&room_name, &default_room, &user_name, Admission::Password(None)))
+                    };
+                    b!(socket.send(Message::Text(notice.into())).await);
+                    let joined = match joined {
+                        Ok(joined) => joined,
+                        Err(e) => {
+                            let _ = socket.send(Message::Text(e.to_string().into())).await;
+                            let _ = socket.send(Message::Close(None)).await;
+                            break Ok(());
+                        }
+                    };
+                    tx = joined.tx;
+                    rx = joined.rx;
+                    info!(%user_name, from = %room_name, to = %default_room, gone, "removed from room");
+                    room_name = default_room.clone();
+                    b!(send_backlog(&mut socket, joined.topic.as_deref(), &joined.history, output).await);
+                    b!(rooms.notify_room(&room_name, &tx, MessageKind::Join, &user_name, format!("{user_name} has joined {room_name}.")));
+                }
+                Control::RoomRenamed { from, to } => {
+                    if from == room_name {
+                        room_name = to;
+                    }
+                }
+            },
+            _ = shutdown.cancelled() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break Ok(());
+            },
+            _ = heartbeat.tick() => {
+                if last_activity.elapsed() >= IDLE_TIMEOUT {
+                    info!(%user_name, "closing idle connection");
+                    let _ = socket.send(Message::Text("Disconnected for inactivity.".into())).await;
+                    let _ = socket.send(Message::Close(None)).await;
+                    break Ok(());
+                }
+                // a subscription's sender keeps its channel open, so a deleted room is noticed here
+                let gone = subscribed
+                    .iter()
+                    .filter(|(_, room_tx)| rooms.name_of(room_tx).is_none())
+                    .map(|(label, _)| label.clone())
+                    .collect::<Vec<_>>();
+                for label in &gone {
+                    subscribed.remove(label);
+                    subscriptions.remove(label);
+                }
+                if !gone.is_empty() {
+                    b!(socket.send(Message::Text(format!("Unsubscribed from rooms that no longer exist: {}", gone.join(", ")).into())).await);
+                }
+                if rooms.room_of(&tx, &user_name).is_none() {
+                    let _ = handle.control.send(Control::RoomGone { room: room_name.clone() });
+                }
+                if let Some(monitor) = &mut monitor {
+                    monitor.sync(&rooms);
+                }
+                if unanswered.exhausted() {
+                    info!(%user_name, missed = unanswered.count, "closing unresponsive connection");
+                    let _ = socket.send(Message::Close(None)).await;
+                    break Ok(());
+                }
+                unanswered.sent();
+                let sent = connected_at.elapsed().as_micros() as u64;
+                b!(socket.send(Message::Ping(Bytes::copy_from_slice(&sent.to_be_bytes()))).await);
+            }
+        }
+    };
+
+    let farewell = match &quit_reason {
+        Some(reason) => format!("{user_name} has left: {reason}"),
+        None => format!("{user_name} has left the chat."),
+    };
+    let _ = rooms.notify_room(&room_name, &tx, MessageKind::Leave, &user_name, farewell);
+    existing.remove(&user_name);
+    announce_presence(&presence, &user_name, false);
+    rooms.leave(&room_name, &user_name);
+    resumes.store(resume_token, &user_name, &room_name, &tx);
+    direct_messages.forget(&user_name);
+    info!(%user_name, %room_name, "connection closed");
+    gauge!("chat_active_connections").decrement(1);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::SinkExt;
+    use tokio::io::AsyncReadExt;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
+
+    // how long a test waits for a frame it expects before failing
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn test_config() -> Config {
+        Config {
+            admin_password: None,
+            welcome: DEFAULT_WELCOME_MSG.to_owned(),
+            filtered_words: HashSet::new(),
+            snapshot: None,
+            max_file_bytes: 1024,
+            heartbeat_interval: Duration::from_secs(15),
+            max_missed_pongs: 3,
+            send_timeout: Duration::from_secs(10),
+            room_creations: 5,
+        }
+    }
+
+    fn test_state(rooms: Rooms, config: Config) -> AppState {
+        AppState {
+            rooms,
+            names: Names::new(Duration::from_secs(60)),
+            invites: Invites::new(),
+            resumes: Resumes::new(),
+            direct_messages: DirectMessages::new(),
+            http_limits: HttpLimits::new(),
+            presence: broadcast::channel(PRESENCE_CAPACITY).0,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            connection_slots: Arc::new(Semaphore::new(100)),
+            config: Arc::new(config),
+            // not installed, so the metrics macros in tests stay no-ops
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+            started: Instant::now(),
+        }
+    }
+
+    async fn serve(state: AppState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router(state).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        addr
+    }
+
+    async fn start() -> SocketAddr {
+        serve(test_state(Rooms::new(None, None, "main"), test_config())).await
+    }
+
+    // a bare-bones HTTP/1.1 request; returns the status code and body
+    async fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_owned())
+    }
+
+    struct Client {
+        ws: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    }
+
+    impl Client {
+        // returns once the connection has joined the default room and been greeted
+        async fn connect(addr: SocketAddr) -> Self {
+            let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+                .await
+                .unwrap();
+            let mut client = Client { ws };
+            client.recv_until("/resume").await;
+            client
+        }
+        async fn named(addr: SocketAddr, name: &str) -> Self {
+            let mut client = Self::connect(addr).await;
+            client.send(&format!("/name {name}")).await;
+            client.recv_until(&format!("is now {name}")).await;
+            client
+        }
+        async fn send(&mut self, text: &str) {
+            self.ws
+                .send(tungstenite::Message::text(text))
+                .await
+                .unwrap();
+        }
+        // the next text frame, skipping pings and file frames
+        async fn recv(&mut self) -> String {
+            loop {
+                let frame = time::timeout(RECV_TIMEOUT, self.ws.next())
+                    .await
+                    .expect("timed out waiting for a message")
+                    .expect("connection closed")
+                    .unwrap();
+                if let tungstenite::Message::Text(text) = frame {
+                    return text.to_string();
+                }
+            }
+        }
+        // every text frame up to and including the first containing `needle`
+        async fn recv_until(&mut self, needle: &str) -> Vec<String> {
+            let mut seen = Vec::new();
+            loop {
+                let text = self.recv().await;
+                let found = text.contains(needle);
+                seen.push(text);
+                if found {
+                    return seen;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_not_broadcast() {
+        let addr = start().await;
+        let mut sender = Client::connect(addr).await;
+        let mut listener = Client::connect(addr).await;
+        let oversized = "x".repeat(MAX_MESSAGE_LEN + 1);
+        sender.send(&oversized).await;
+        sender.recv_until(&too_long_msg()).await;
+        // room messages arrive in order, so anything broadcast before this would show up first
+        sender.send("marker").await;
+        let seen = listener.recv_until("marker").await;
+        assert!(seen.iter().all(|text| !text.contains(&oversized)));
+    }
+
+    #[test]
+    fn rate_limiter_throttles_after_the_allowance() {
+        let mut limiter = RateLimiter::new();
+        let allowed = (0..10).filter(|_| limiter.allow()).count();
+        assert_eq!(allowed, RATE_LIMIT_MESSAGES as usize);
+    }
+
+    #[test]
+    fn rate_limiter_resets_after_the_window() {
+        let mut limiter = RateLimiter::new();
+        while limiter.allow() {}
+        limiter.window_start -= RATE_LIMIT_WINDOW;
+        assert!(limiter.allow());
+    }
+
+    #[tokio::test]
+    async fn away_checks_length_and_rate() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        client
+            .send(&format!("/away {}", "x".repeat(MAX_MESSAGE_LEN + 1)))
+            .await;
+        client.recv_until(&too_long_msg()).await;
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            client.send("/away lunch").await;
+        }
+        client.send("/away lunch").await;
+        client.recv_until(TOO_FAST_MSG).await;
+    }
+
+    #[test]
+    fn validate_name_rejects_bad_names() {
+        assert!(validate_name("").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert!(validate_name("line\nbreak").is_err());
+        assert!(validate_name("tab\there").is_err());
+        assert!(validate_name("   ").is_err());
+        assert!(validate_name(&"é".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(validate_name("Ada Lovelace").is_ok());
+    }
+
+    #[test]
+    fn reserved_names_ignore_case() {
+        assert!(is_reserved("admin"));
+        assert!(is_reserved("SYSTEM"));
+        assert!(!is_reserved("administrator"));
+    }
+
+    #[tokio::test]
+    async fn name_admin_is_rejected() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        client.send("/name admin").await;
+        client.recv_until("That name is reserved.").await;
+        client.send("/whoami").await;
+        let reply = client.recv_until("You are ").await.pop().unwrap();
+        assert!(!reply.starts_with("You are admin "));
+    }
+
+    #[tokio::test]
+    async fn admin_commands_need_the_password() {
+        let config = Config {
+            admin_password: Some(PasswordHash::new("hunter2")),
+            ..test_config()
+        };
+        let addr = serve(test_state(Rooms::new(None, None, "main"), config)).await;
+        let mut client = Client::connect(addr).await;
+        client.send("/announce hello").await;
+        client.recv_until("Only admins may use /announce.").await;
+        client.send("/admin guess").await;
+        client.recv_until("Incorrect admin password.").await;
+        client.send("/admin hunter2").await;
+        client.recv_until("You are now an admin").await;
+        client.send("/announce hello").await;
+        client.recv_until("[Announcement] hello").await;
+    }
+
+    #[tokio::test]
+    async fn topic_changes_are_rate_limited() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        for n in 0..RATE_LIMIT_MESSAGES {
+            client.send(&format!("/topic topic {n}")).await;
+        }
+        client.send("/topic one too many").await;
+        client.recv_until(TOO_FAST_MSG).await;
+        client.send("/topic").await;
+        let reply = client.recv_until("Topic: ").await.pop().unwrap();
+        assert_eq!(reply, format!("Topic: topic {}", RATE_LIMIT_MESSAGES - 1));
+    }
+
+    #[tokio::test]
+    async fn health_reports_rooms_and_users() {
+        let addr = start().await;
+        let _client = Client::connect(addr).await;
+        let (status, body) = http(addr, "GET", "/health", "").await;
+        assert_eq!(status, 200);
+        let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            health,
+            serde_json::json!({"status": "ok", "rooms": 1, "users": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn kick_sends_the_user_back_to_the_default_room() {
+        let addr = start().await;
+        let mut owner = Client::named(addr, "olive").await;
+        let mut guest = Client::named(addr, "gus").await;
+        owner.send("/join den").await;
+        owner.recv_until("You created room den.").await;
+        guest.send("/join den").await;
+        guest.recv_until("You joined room den.").await;
+        owner.send("/kick gus").await;
+        guest.recv_until("You have been removed from den.").await;
+        guest.send("/whoami").await;
+        guest.recv_until("You are gus in main.").await;
+        // a kick is not a ban
+        guest.send("/join den").await;
+        guest.recv_until("You joined room den.").await;
+    }
+
+    #[tokio::test]
+    async fn banned_users_cannot_rejoin() {
+        let addr = start().await;
+        let mut owner = Client::named(addr, "olive").await;
+        let mut guest = Client::named(addr, "gus").await;
+        owner.send("/join den").await;
+        owner.recv_until("You created room den.").await;
+        guest.send("/join den").await;
+        guest.recv_until("You joined room den.").await;
+        owner.send("/ban gus").await;
+        guest.recv_until("You have been removed from den.").await;
+        guest.send("/join den").await;
+        guest.recv_until(&JoinError::Banned.to_string()).await;
+        // absent users can be banned ahead of time, but only under a valid name
+        owner
+            .send(&format!("/ban {}", "x".repeat(MAX_NAME_LEN + 1)))
+            .await;
+        owner.recv_until("Name is too long").await;
+    }
+
+    #[test]
+    fn near_miss_command_names_are_chat() {
+        assert_eq!(Command::parse("/users"), Some(Command::Users));
+        assert_eq!(Command::parse("/rename-self"), Some(Command::Name));
+        for word in [
+            "/userscript",
+            "/joinx",
+            "/Users",
+            "users",
+            "/",
+            "",
+            "/rooms2",
+        ] {
+            assert_eq!(Command::parse(word), None, "{word:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn near_miss_commands_are_sent_as_chat() {
+        let addr = start().await;
+        let mut client = Client::named(addr, "ann").await;
+        client.send("/userscript").await;
+        client.recv_until("ann: /userscript").await;
+    }
+
+    #[test]
+    fn mask_words_masks_whole_words_ignoring_case() {
+        let words = HashSet::from(["darn".to_owned(), "heck".to_owned()]);
+        assert_eq!(
+            mask_words("Darn it, what the HECK!", &words),
+            "**** it, what the ****!"
+        );
+        // only whole words are masked
+        assert_eq!(mask_words("darned heckler", &words), "darned heckler");
+        assert_eq!(mask_words("heck", &words), "****");
+        assert_eq!(mask_words("darn it", &HashSet::new()), "darn it");
+    }
+
+    #[test]
+    fn first_joiner_owns_a_restored_room() {
+        let rooms = Rooms::new(None, None, "main");
+        rooms.restore(vec![RoomSnapshot {
+            name: "den".to_owned(),
+            topic: None,
+            history: Vec::new(),
+            password: None,
+        }]);
+        assert_eq!(rooms.owner("den"), None);
+        rooms
+            .join("den", "alice", Admission::Password(None))
+            .unwrap();
+        rooms.join("den", "bob", Admission::Password(None)).unwrap();
+        assert_eq!(rooms.owner("den").as_deref(), Some("alice"));
+        rooms
+            .join("main", "carol", Admission::Password(None))
+            .unwrap();
+        assert_eq!(rooms.owner("main"), None);
+    }
+
+    #[tokio::test]
+    async fn name_change_rolls_back_when_the_room_is_gone() {
+        let state = test_state(Rooms::new(None, None, "main"), test_config());
+        let addr = serve(state.clone()).await;
+        let mut client = Client::named(addr, "ann").await;
+        client.send("/join den").await;
+        client.recv_until("You created room den.").await;
+        // as if the room were deleted by another connection in the meantime
+        state.rooms.map.write().unwrap().remove("den");
+        client.send("/name bea").await;
+        client
+            .recv_until("Could not change your name: your room no longer exists.")
+            .await;
+        assert!(state.names.handle("ann").is_some());
+        assert!(state.names.handle("bea").is_none());
+    }
+
+    #[tokio::test]
+    async fn messages_to_an_empty_room_are_not_delivered() {
+        let addr = start().await;
+        let mut sender = Client::connect(addr).await;
+        sender.send("hello?").await;
+        sender.recv_until(NOT_DELIVERED_MSG).await;
+        let _listener = Client::connect(addr).await;
+        sender.send("hello!").await;
+        sender.send("/whoami").await;
+        let seen = sender.recv_until("You are ").await;
+        assert!(seen.iter().all(|text| text != NOT_DELIVERED_MSG));
+    }
+
+    #[test]
+    fn file_frames_carry_their_header_length() {
+        let notice = FileNotice {
+            from: "ann",
+            filename: "cat.png",
+        };
+        let frame = encode_file_frame(&notice, b"meow").unwrap();
+        let header_len = usize::from(u16::from_be_bytes([frame[0], frame[1]]));
+        let header: serde_json::Value = serde_json::from_slice(&frame[2..2 + header_len]).unwrap();
+        assert_eq!(
+            header,
+            serde_json::json!({"from": "ann", "filename": "cat.png"})
+        );
+        assert_eq!(&frame[2 + header_len..], b"meow");
+    }
+
+    #[test]
+    fn oversized_file_headers_are_refused() {
+        let filename = "x".repeat(usize::from(u16::MAX));
+        let notice = FileNotice {
+            from: "ann",
+            filename: &filename,
+        };
+        assert!(encode_file_frame(&notice, b"").is_none());
+        assert!(validate_filename(&filename).is_err());
+        assert!(validate_filename(&"x".repeat(MAX_FILENAME_LEN)).is_ok());
+        assert!(validate_filename("../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn list_users_on_a_deleted_room_is_none() {
+        let rooms = Rooms::new(None, None, "main");
+        rooms.join("den", "ann", Admission::Password(None)).unwrap();
+        assert_eq!(rooms.list_users("den"), Some(vec!["ann".to_owned()]));
+        rooms.leave("den", "ann");
+        assert_eq!(rooms.list_users("den"), None);
+        assert_eq!(rooms.describe_users("den"), None);
+    }
+
+    #[tokio::test]
+    async fn format_switches_between_text_and_json() {
+        let addr = start().await;
+        let mut client = Client::named(addr, "ann").await;
+        client.send("hi").await;
+        let line = client.recv_until("ann: hi").await.pop().unwrap();
+        assert!(
+            line.starts_with('[') && line.ends_with("UTC] ann: hi"),
+            "{line}"
+        );
+        assert!(serde_json::from_str::<ChatMessage>(&line).is_err());
+
+        client.send("/format json").await;
+        client
+            .recv_until("Messages will now be sent as json.")
+            .await;
+        client.send("yo").await;
+        let json = client.recv_until("ann: yo").await.pop().unwrap();
+        let msg: ChatMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg.message, "ann: yo");
+        assert_eq!(msg.kind, MessageKind::Chat);
+        assert_eq!(msg.sender.as_deref(), Some("ann"));
+    }
+
+    #[tokio::test]
+    async fn typing_repeats_are_debounced() {
+        let addr = start().await;
+        let mut typist = Client::named(addr, "ann").await;
+        let mut listener = Client::connect(addr).await;
+        typist.send("/typing").await;
+        typist.send("/typing").await;
+        typist.send("marker").await;
+        let seen = listener.recv_until("ann: marker").await;
+        let notices = seen
+            .iter()
+            .filter(|text| text.contains("ann is typing..."))
+            .count();
+        assert_eq!(notices, 1);
+    }
+
+    #[test]
+    fn rejoin_skips_the_password_only_for_the_same_room() {
+        let rooms = Rooms::new(None, None, "main");
+        let ann = rooms
+            .join("den", "ann", Admission::Password(Some("pw")))
+            .unwrap();
+        rooms
+            .join("den", "bob", Admission::Password(Some("pw")))
+            .unwrap();
+        rooms.leave("den", "ann");
+        rooms
+            .join("den", "ann", Admission::Rejoin(&ann.tx))
+            .unwrap();
+
+        // once everyone leaves, the name is free for a new room with a new password
+        rooms.leave("den", "ann");
+        rooms.leave("den", "bob");
+        rooms
+            .join("den", "eve", Admission::Password(Some("other")))
+            .unwrap();
+        assert!(matches!(
+            rooms.join("den", "ann", Admission::Rejoin(&ann.tx)),
+            Err(JoinError::IncorrectPassword)
+        ));
+    }
+
+    #[test]
+    fn unanswered_pings_count_up_and_reset() {
+        let mut pings = UnansweredPings::new(3);
+        pings.sent();
+        pings.sent();
+        assert!(!pings.exhausted());
+        pings.answered();
+        pings.sent();
+        pings.sent();
+        assert!(!pings.exhausted());
+        pings.sent();
+        assert!(pings.exhausted());
+    }
+
+    #[test]
+    fn validate_room_name_rejects_bad_names() {
+        assert!(validate_room_name("").is_err());
+        assert!(validate_room_name(&"r".repeat(MAX_ROOM_NAME_LEN + 1)).is_err());
+        assert!(validate_room_name("bad\u{7}bell").is_err());
+        assert!(validate_room_name("two words").is_err());
+        assert!(validate_room_name(&"r".repeat(MAX_ROOM_NAME_LEN)).is_ok());
+        assert_eq!(parse_room_name("lobby"), Ok("lobby".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn echo_off_hides_only_your_own_messages() {
+        let addr = start().await;
+        let mut ann = Client::named(addr, "ann").await;
+        let mut bob = Client::named(addr, "bob").await;
+        ann.send("/echo off").await;
+        ann.recv_until("no longer be echoed").await;
+        ann.send("quiet").await;
+        bob.recv_until("ann: quiet").await;
+        bob.send("marker").await;
+        let seen = ann.recv_until("bob: marker").await;
+        assert!(seen.iter().all(|text| !text.contains("ann: quiet")));
+
+        ann.send("/echo on").await;
+        ann.recv_until("will be echoed").await;
+        ann.send("loud").await;
+        ann.recv_until("ann: loud").await;
+    }
+
+    fn test_handle() -> UserHandle {
+        UserHandle {
+            inbox: broadcast::channel(1).0,
+            control: mpsc::unbounded_channel().0,
+            files: mpsc::channel(1).0,
+        }
+    }
+
+    #[test]
+    fn released_names_cool_down_for_other_connections() {
+        let names = Names::new(Duration::from_secs(60));
+        let (ann, eve) = (test_handle(), test_handle());
+        assert!(names.insert("Ann".to_owned(), ann.clone()));
+        assert!(names.remove("Ann"));
+        assert!(!names.insert("ann".to_owned(), eve.clone()));
+        assert!(!names.rename("Eve", "ANN".to_owned(), &eve));
+        // the connection that gave the name up may take it straight back
+        assert!(names.insert("ann".to_owned(), ann));
+    }
+
+    #[test]
+    fn released_names_are_free_once_the_cooldown_passes() {
+        let names = Names::new(Duration::from_millis(20));
+        assert!(names.insert("ann".to_owned(), test_handle()));
+        names.remove("ann");
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(names.insert("ann".to_owned(), test_handle()));
+
+        let names = Names::new(Duration::ZERO);
+        assert!(names.insert("ann".to_owned(), test_handle()));
+        names.remove("ann");
+        assert!(names.insert("ann".to_owned(), test_handle()));
+    }
+
+    #[test]
+    fn get_unique_falls_back_once_the_names_run_out() {
+        let names = Names::new(Duration::from_secs(60));
+        let taken = (0..people_count())
+            .map(|i| rust_final_project::person_at(i).unwrap())
+            .collect::<Vec<_>>();
+        for name in &taken {
+            assert!(names.insert((*name).to_owned(), test_handle()));
+        }
+        let name = names.get_unique(&test_handle());
+        assert!(!taken.contains(&name.as_str()));
+        assert!(names.handle(&name).is_some());
+    }
+
+    #[test]
+    fn every_command_is_listed_once() {
+        let all = [
+            Command::Name,
+            Command::Join,
+            Command::Invite,
+            Command::JoinCode,
+            Command::Subscribe,
+            Command::Unsubscribe,
+            Command::Resume,
+            Command::Msg,
+            Command::DmHistory,
+            Command::Me,
+            Command::Edit,
+            Command::Delete,
+            Command::RenameRoom,
+            Command::Topic,
+            Command::Poll,
+            Command::Vote,
+            Command::PollResult,
+            Command::Away,
+            Command::Back,
+            Command::Typing,
+            Command::Kick,
+            Command::Ban,
+            Command::Transfer,
+            Command::Users,
+            Command::AllUsers,
+            Command::Rooms,
+            Command::Admin,
+            Command::Announce,
+            Command::Push,
+            Command::Monitor,
+            Command::Unmonitor,
+            Command::Format,
+            Command::Tz,
+            Command::Echo,
+            Command::Presence,
+            Command::Silence,
+            Command::Unsilence,
+            Command::WhoAmI,
+            Command::MyRoom,
+            Command::Stats,
+            Command::Names,
+            Command::Ping,
+            Command::Help,
+            Command::Quit,
+        ];
+        // `all` is kept by hand, so a variant left out of both it and COMMANDS goes unnoticed;
+        // the checks are that each listed command has exactly one spec, and no spec is extra
+        for command in all {
+            let specs = COMMANDS
+                .iter()
+                .filter(|spec| spec.command == command)
+                .collect::<Vec<_>>();
+            assert_eq!(specs.len(), 1, "{command:?}");
+            assert!(HELP_MSG.contains(specs[0].usage), "{command:?}");
+        }
+        assert_eq!(COMMANDS.len(), all.len());
+    }
+
+    #[test]
+    fn names_and_aliases_find_their_command() {
+        let mut words = HashSet::new();
+        for spec in COMMANDS {
+            for word in std::iter::once(&spec.name).chain(spec.aliases) {
+                assert!(words.insert(*word), "{word} is listed twice");
+                assert_eq!(Command::parse(word), Some(spec.command));
+            }
+            assert!(command_help(&spec.name[1..]).unwrap().contains(spec.usage));
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_log_lines_match_the_broadcasts() {
+        let path = std::env::temp_dir().join(format!("chat-audit-{}.jsonl", random_code(8)));
+        let rooms = Rooms::new(Some(AuditLog::open(&path).await.unwrap()), None, "main");
+        let joined = rooms.join("den", "ann", Admission::Password(None)).unwrap();
+        let mut rx = joined.rx;
+        for text in ["ann: hello", "ann: \"quoted\"\nsecond line"] {
+            let msg = ChatMessage::new(MessageKind::Chat, Some("ann"), text.to_owned());
+            rooms.send("den", &joined.tx, msg).unwrap();
+        }
+        let broadcast = [rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let log = tokio::fs::read_to_string(&path).await.unwrap();
+            lines = log.lines().map(str::to_owned).collect::<Vec<_>>();
+            if lines.len() == broadcast.len() {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(lines.len(), broadcast.len());
+        for (line, msg) in lines.iter().zip(&broadcast) {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["room"], "den");
+            assert_eq!(record["message"], msg.message);
+            assert_eq!(record["sender"], "ann");
+            assert_eq!(record["kind"], "chat");
+            assert_eq!(record["seq"], msg.seq);
+            assert_eq!(record["timestamp"], msg.timestamp);
+        }
+    }
+
+    fn post_body(sender: &str, text: &str) -> String {
+        serde_json::json!({"sender": sender, "text": text}).to_string()
+    }
+
+    #[tokio::test]
+    async fn posted_messages_reach_websocket_users() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/rooms/main/messages",
+            &post_body("ci-bot", "build passed"),
+        )
+        .await;
+        assert_eq!(status, 204);
+        client.recv_until("ci-bot: build passed").await;
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/rooms/nowhere/messages",
+            &post_body("ci-bot", "hello?"),
+        )
+        .await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn posting_as_a_taken_or_cooling_name_conflicts() {
+        let addr = start().await;
+        let mut client = Client::named(addr, "ann").await;
+        let body = post_body("Ann", "it's me");
+        let (status, _) = http(addr, "POST", "/rooms/main/messages", &body).await;
+        assert_eq!(status, 409);
+        client.send("/name bea").await;
+        client.recv_until("is now bea").await;
+        let (status, _) = http(addr, "POST", "/rooms/main/messages", &body).await;
+        assert_eq!(status, 409);
+    }
+
+    #[tokio::test]
+    async fn posting_is_rate_limited_per_address() {
+        let addr = start().await;
+        let _client = Client::connect(addr).await;
+        for i in 0..RATE_LIMIT_MESSAGES {
+            let (status, _) = http(
+                addr,
+                "POST",
+                "/rooms/main/messages",
+                &post_body(&format!("bot{i}"), "hi"),
+            )
+            .await;
+            assert_eq!(status, 204);
+        }
+        // a fresh sender name does not buy a fresh allowance
+        let (status, body) = http(
+            addr,
+            "POST",
+            "/rooms/main/messages",
+            &post_body("other-bot", "hi"),
+        )
+        .await;
+        assert_eq!((status, body.as_str()), (429, TOO_FAST_MSG));
+    }
+
+    #[tokio::test]
+    async fn posting_is_refused_for_locked_rooms_and_banned_senders() {
+        let state = test_state(Rooms::new(None, None, "main"), test_config());
+        let addr = serve(state.clone()).await;
+        let mut owner = Client::connect(addr).await;
+        owner.send("/join vault secret").await;
+        owner.recv_until("You created room vault.").await;
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/rooms/vault/messages",
+            &post_body("bot", "hi"),
+        )
+        .await;
+        assert_eq!(status, 403);
+        let _listener = Client::connect(addr).await;
+        state.rooms.ban("main", "bot").unwrap();
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/rooms/main/messages",
+            &post_body("BOT", "hi"),
+        )
+        .await;
+        assert_eq!(status, 403);
+        let (status, _) = http(
+            addr,
+            "POST",
+            "/rooms/main/messages",
+            &post_body("other-bot", "hi"),
+        )
+        .await;
+        assert_eq!(status, 204);
+    }
+
+    #[tokio::test]
+    async fn rest_lists_rooms_and_their_users() {
+        let addr = start().await;
+        let mut ann = Client::named(addr, "ann").await;
+        let _bob = Client::named(addr, "bob").await;
+        ann.send("/join den").await;
+        ann.recv_until("You created room den.").await;
+
+        let (status, body) = http(addr, "GET", "/rooms", "").await;
+        assert_eq!(status, 200);
+        let rooms: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let names = rooms
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| {
+                (
+                    room["name"].as_str().unwrap(),
+                    room["users"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, [("den", 1), ("main", 1)]);
+
+        let (status, body) = http(addr, "GET", "/rooms/den/users", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"room": "den", "users": ["ann"]})
+        );
+        let (status, body) = http(addr, "GET", "/rooms/nowhere/users", "").await;
+        assert_eq!((status, body.as_str()), (404, "No such room."));
+    }
+
+    #[tokio::test]
+    async fn silenced_users_are_not_forwarded() {
+        let addr = start().await;
+        let mut listener = Client::named(addr, "lee").await;
+        let mut ann = Client::named(addr, "Ann Marie").await;
+        let mut bob = Client::named(addr, "bob").await;
+        listener.send("/silence ann marie").await;
+        listener
+            .recv_until("You will no longer see messages from ann marie.")
+            .await;
+        ann.send("psst").await;
+        bob.recv_until("Ann Marie: psst").await;
+        bob.send("marker").await;
+        let seen = listener.recv_until("bob: marker").await;
+        assert!(seen.iter().all(|text| !text.contains("Ann Marie: psst")));
+
+        listener.send("/unsilence Ann Marie").await;
+        listener
+            .recv_until("You will see messages from Ann Marie again.")
+            .await;
+        ann.send("hello again").await;
+        listener.recv_until("Ann Marie: hello again").await;
+    }
+
+    #[test]
+    fn joins_past_the_room_cap_are_refused() {
+        let rooms = Rooms::new(None, Some(3), "main");
+        rooms
+            .join("main", "ann", Admission::Password(None))
+            .unwrap();
+        rooms.join("a", "ann", Admission::Password(None)).unwrap();
+        rooms.join("b", "ann", Admission::Password(None)).unwrap();
+        assert!(matches!(
+            rooms.join("c", "ann", Admission::Password(None)),
+            Err(JoinError::RoomLimit)
+        ));
+        // existing rooms can still be joined
+        rooms.join("a", "bob", Admission::Password(None)).unwrap();
+        rooms.leave("b", "ann");
+        rooms.join("c", "ann", Admission::Password(None)).unwrap();
+    }
+
+    #[test]
+    fn the_default_room_is_exempt_from_the_room_cap() {
+        let rooms = Rooms::new(None, Some(1), "main");
+        rooms.join("a", "ann", Admission::Password(None)).unwrap();
+        rooms
+            .join("main", "bob", Admission::Password(None))
+            .unwrap();
+        assert_eq!(rooms.count(), 2);
+    }
+
+    #[test]
+    fn parse_poll_reads_question_and_options() {
+        assert_eq!(
+            parse_poll(r#""Lunch where?" tacos pizza"#),
+            Some((
+                "Lunch where?".to_owned(),
+                vec!["tacos".to_owned(), "pizza".to_owned()]
+            ))
+        );
+        assert_eq!(
+            parse_poll("Lunch? tacos pizza"),
+            Some((
+                "Lunch?".to_owned(),
+                vec!["tacos".to_owned(), "pizza".to_owned()]
+            ))
+        );
+        assert_eq!(parse_poll(r#""Lunch?" tacos"#), None);
+        assert_eq!(parse_poll(r#""" tacos pizza"#), None);
+        assert_eq!(parse_poll(r#""unterminated tacos pizza"#), None);
+        let too_many = (0..=MAX_POLL_OPTIONS)
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(parse_poll(&format!("Pick? {}", too_many.join(" "))), None);
+    }
+
+    #[test]
+    fn votes_are_tallied_and_revotes_replace() {
+        let rooms = Rooms::new(None, None, "main");
+        let joined = rooms.join("den", "ann", Admission::Password(None)).unwrap();
+        let tx = joined.tx;
+        assert!(matches!(
+            rooms.vote("den", &tx, "ann", 1),
+            Err(RoomError::NoPoll)
+        ));
+        let options = vec!["tacos".to_owned(), "pizza".to_owned()];
+        rooms
+            .start_poll("den", &tx, "Lunch?".to_owned(), options)
+            .unwrap();
+        assert_eq!(rooms.vote("den", &tx, "ann", 1).unwrap(), "tacos");
+        assert_eq!(rooms.vote("den", &tx, "bob", 1).unwrap(), "tacos");
+        assert_eq!(rooms.vote("den", &tx, "Ann", 2).unwrap(), "pizza");
+        assert!(matches!(
+            rooms.vote("den", &tx, "ann", 3),
+            Err(RoomError::NoSuchOption { options: 2 })
+        ));
+        assert!(matches!(
+            rooms.vote("den", &tx, "ann", 0),
+            Err(RoomError::NoSuchOption { options: 2 })
+        ));
+        assert_eq!(
+            rooms.poll_result("den").unwrap(),
+            "Poll: Lunch?\n  1. tacos - 1 vote\n  2. pizza - 1 vote"
+        );
+        rooms.vote("den", &tx, "cat", 2).unwrap();
+        assert_eq!(
+            rooms.poll_result("den").unwrap(),
+            "Poll: Lunch?\n  1. tacos - 1 vote\n  2. pizza - 2 votes"
+        );
+    }
+
+    #[test]
+    fn a_new_poll_replaces_the_old_one() {
+        let rooms = Rooms::new(None, None, "main");
+        let tx = rooms
+            .join("den", "ann", Admission::Password(None))
+            .unwrap()
+            .tx;
+        let options = || vec!["yes".to_owned(), "no".to_owned()];
+        rooms
+            .start_poll("den", &tx, "First?".to_owned(), options())
+            .unwrap();
+        rooms.vote("den", &tx, "ann", 1).unwrap();
+        rooms
+            .start_poll("den", &tx, "Second?".to_owned(), options())
+            .unwrap();
+        assert_eq!(
+            rooms.poll_result("den").unwrap(),
+            "Poll: Second?\n  1. yes - 0 votes\n  2. no - 0 votes"
+        );
+    }
+
+    #[test]
+    fn room_creations_are_limited_per_window() {
+        let mut creations = RoomCreations::new(2);
+        for _ in 0..2 {
+            assert!(creations.allow());
+            creations.record();
+        }
+        assert!(!creations.allow());
+        // creations older than the window stop counting
+        creations.times[0] -= ROOM_CREATION_WINDOW;
+        assert!(creations.allow());
+
+        let mut unlimited = RoomCreations::new(0);
+        for _ in 0..100 {
+            unlimited.record();
+        }
+        assert!(unlimited.allow());
+    }
+
+    #[tokio::test]
+    async fn rapid_room_creation_is_throttled() {
+        let config = Config {
+            room_creations: 2,
+            ..test_config()
+        };
+        let addr = serve(test_state(Rooms::new(None, None, "main"), config)).await;
+        let _stays_in_main = Client::connect(addr).await;
+        let mut client = Client::connect(addr).await;
+        for room in ["a", "b"] {
+            client.send(&format!("/join {room}")).await;
+            client
+                .recv_until(&format!("You created room {room}."))
+                .await;
+        }
+        client.send("/join c").await;
+        client.recv_until(CREATING_TOO_FAST_MSG).await;
+        // joining a room that exists creates nothing
+        client.send("/join main").await;
+        client.recv_until("You joined room main.").await;
+    }
+
+    #[test]
+    fn awkward_text_renders_in_both_formats() {
+        let text = "quote \" backslash \\ nul \0 newline \n emoji 🦀 bidi \u{202e}";
+        let msg = ChatMessage::new(MessageKind::Chat, Some("ann\u{1f}"), text.to_owned());
+        let json = Output {
+            format: OutputFormat::Json,
+            ..Output::new()
+        };
+        let rendered: ChatMessage = serde_json::from_str(&render(&msg, json)).unwrap();
+        assert_eq!(rendered.message, text);
+        assert_eq!(rendered.sender.as_deref(), Some("ann\u{1f}"));
+
+        let mut msg = ChatMessage::system("hello".to_owned());
+        msg.timestamp = 1_000;
+        assert_eq!(
+            render(&msg, Output::new()),
+            "[1970-01-01 00:00:01.000 UTC] hello"
+        );
+        // ChatMessage always serializes, so the failure is injected
+        let failed = <serde_json::Error as serde::ser::Error>::custom("unserializable");
+        assert_eq!(
+            json_or_text(&msg, json, Err(failed)),
+            "[1970-01-01 00:00:01.000 UTC] hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn quit_reason_appears_in_the_farewell() {
+        let addr = start().await;
+        let mut listener = Client::connect(addr).await;
+        let mut ann = Client::named(addr, "ann").await;
+        ann.send("/quit off to lunch").await;
+        listener.recv_until("ann has left: off to lunch").await;
+        let mut bob = Client::named(addr, "bob").await;
+        bob.send("/quit").await;
+        listener.recv_until("bob has left the chat.").await;
+    }
+
+    #[tokio::test]
+    async fn room_errors_are_replied_to_without_disconnecting() {
+        let state = test_state(Rooms::new(None, None, "main"), test_config());
+        let addr = serve(state.clone()).await;
+        let mut client = Client::named(addr, "ann").await;
+        client.send("/join den").await;
+        client.recv_until("You created room den.").await;
+        state.rooms.map.write().unwrap().remove("den");
+        client.send("/away lunch").await;
+        client.recv_until(ROOM_GONE_MSG).await;
+        client.recv_until("so you have been moved to main.").await;
+        client.send("/whoami").await;
+        client.recv_until("You are ann in main.").await;
+
+        client.send("/join cave").await;
+        client.recv_until("You created room cave.").await;
+        state.rooms.map.write().unwrap().remove("cave");
+        client.send("/vote 1").await;
+        client.recv_until(ROOM_GONE_MSG).await;
+        client.recv_until("so you have been moved to main.").await;
+        client.send("/whoami").await;
+        client.recv_until("You are ann in main.").await;
+    }
+
+    #[tokio::test]
+    async fn bare_join_answers_with_its_usage() {
+        let state = test_state(Rooms::new(None, None, "main"), test_config());
+        let addr = serve(state.clone()).await;
+        let mut client = Client::connect(addr).await;
+        client.send("/join").await;
+        client.recv_until("Usage: /join [ROOM] [PASSWORD]").await;
+        client.send("/join   ").await;
+        client.recv_until("Usage: /join [ROOM] [PASSWORD]").await;
+        assert!(state.rooms.channel("").is_none());
+        client.send("/whoami").await;
+        client.recv_until(" in main.").await;
+    }
+
+    async fn invite_code(client: &mut Client) -> String {
+        client.send("/invite").await;
+        let line = client.recv_until("Invite code for ").await.pop().unwrap();
+        line.split_once(": ")
+            .unwrap()
+            .1
+            .split(' ')
+            .next()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn invites_skip_the_password_only_for_their_room() {
+        let addr = start().await;
+        let mut ann = Client::named(addr, "ann").await;
+        ann.send("/join den secret").await;
+        ann.recv_until("You created room den.").await;
+        let stale = invite_code(&mut ann).await;
+        let mut bob = Client::named(addr, "bob").await;
+        bob.send("/join den secret").await;
+        bob.recv_until("You joined room den.").await;
+        let code = invite_code(&mut bob).await;
+        let mut eve = Client::named(addr, "eve").await;
+        eve.send(&format!("/join-code {code}")).await;
+        eve.recv_until("eve has joined den.").await;
+        eve.send(&format!("/join-code {code}")).await;
+        eve.recv_until("That invite code is invalid or has expired.")
+            .await;
+
+        for client in [&mut eve, &mut bob, &mut ann] {
+            client.send("/join main").await;
+            client.recv_until(" room main.").await;
+        }
+        // den was deleted once empty, so this is a different room under the old name
+        ann.send("/join den other").await;
+        ann.recv_until("You created room den.").await;
+        eve.send(&format!("/join-code {stale}")).await;
+        eve.recv_until(&JoinError::NoSuchRoom.to_string()).await;
+        eve.send("/whoami").await;
+        eve.recv_until("You are eve in main.").await;
+    }
+
+    #[test]
+    fn default_room_is_never_locked_or_renamed() {
+        let rooms = Rooms::new(None, None, "main");
+        // as if saved while another room was the default
+        rooms.restore(vec![RoomSnapshot {
+            name: "main".to_owned(),
+            topic: None,
+            history: Vec::new(),
+            password: Some(PasswordHash::new("secret")),
+        }]);
+        rooms
+            .join("main", "alice", Admission::Password(None))
+            .unwrap();
+        rooms.leave("main", "alice");
+        assert!(rooms.channel("main").is_none());
+        rooms
+            .join("main", "alice", Admission::Password(Some("secret")))
+            .unwrap();
+        rooms
+            .join("main", "bob", Admission::Password(None))
+            .unwrap();
+
+        rooms
+            .join("den", "carol", Admission::Password(Some("secret")))
+            .unwrap();
+        assert!(matches!(
+            rooms.change_room_name("main", "lobby"),
+            Err(RoomError::DefaultRoom)
+        ));
+        rooms.leave("main", "alice");
+        rooms.leave("main", "bob");
+        assert!(matches!(
+            rooms.change_room_name("den", "main"),
+            Err(RoomError::DefaultRoom)
+        ));
+        assert!(rooms.channel("main").is_none());
+        assert!(rooms.channel("den").is_some());
+    }
+
+    #[test]
+    fn transfer_needs_the_owner_and_a_member() {
+        let rooms = Rooms::new(None, None, "main");
+        rooms
+            .join("den", "alice", Admission::Password(None))
+            .unwrap();
+        rooms.join("den", "bob", Admission::Password(None)).unwrap();
+        rooms
+            .join("main", "carol", Admission::Password(None))
+            .unwrap();
+        assert!(matches!(
+            rooms.transfer("den", "alice", "carol"),
+            Err(RoomError::UserNotFound)
+        ));
+        assert_eq!(rooms.owner("den").as_deref(), Some("alice"));
+        assert!(matches!(
+            rooms.transfer("den", "bob", "bob"),
+            Err(RoomError::NotOwner { .. })
+        ));
+        assert_eq!(rooms.owner("den").as_deref(), Some("alice"));
+        assert_eq!(rooms.transfer("den", "alice", "BOB").unwrap(), "bob");
+        assert_eq!(rooms.owner("den").as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn restored_history_is_capped() {
+        let rooms = Rooms::new(None, None, "main");
+        let history = (1..=HISTORY_LEN as u64 + 10)
+            .map(|seq| {
+                let mut msg = ChatMessage::system(format!("message {seq}"));
+                msg.seq = seq;
+                msg
+            })
+            .collect();
+        rooms.restore(vec![RoomSnapshot {
+            name: "den".to_owned(),
+            topic: None,
+            history,
+            password: None,
+        }]);
+        let joined = rooms
+            .join("den", "alice", Admission::Password(None))
+            .unwrap();
+        assert_eq!(joined.history.len(), HISTORY_LEN);
+        assert_eq!(joined.history[0].seq, 11);
+        rooms
+            .send("den", &joined.tx, ChatMessage::system("later".to_owned()))
+            .unwrap();
+        let joined = rooms.join("den", "bob", Admission::Password(None)).unwrap();
+        assert_eq!(joined.history.len(), HISTORY_LEN);
+        assert_eq!(joined.history[0].seq, 12);
+        assert_eq!(joined.history.last().unwrap().seq, HISTORY_LEN as u64 + 11);
+    }
+}
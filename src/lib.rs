@@ -1,8 +1,157 @@
 mod people;
+mod words;
 
 use people::FAMOUS_PEOPLE;
-use fastrand;
+use words::{ADJECTIVES, NOUNS};
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// Picks names from a fixed pool; `NameGenerator::default()` uses `FAMOUS_PEOPLE`.
+#[derive(Debug, Clone, Copy)]
+pub struct NameGenerator {
+    pool: &'static [&'static str],
+}
+
+impl NameGenerator {
+    /// # Panics
+    ///
+    /// If `pool` is empty.
+    pub fn new(pool: &'static [&'static str]) -> Self {
+        assert!(!pool.is_empty(), "name pool is empty");
+        NameGenerator { pool }
+    }
+
+    /// Any name from the pool.
+    pub fn random(&self) -> String {
+        fastrand::choice(self.pool).unwrap().to_string()
+    }
+
+    /// A name from the pool that is not in `taken`, which it is then added to.
+    /// `None` once every name is taken.
+    pub fn unique(&self, taken: &mut HashSet<String>) -> Option<String> {
+        let free = self
+            .pool
+            .iter()
+            .filter(|name| !taken.contains(**name))
+            .collect::<Vec<_>>();
+        let name = fastrand::choice(free)?.to_string();
+        taken.insert(name.clone());
+        Some(name)
+    }
+}
+
+impl Default for NameGenerator {
+    fn default() -> Self {
+        NameGenerator::new(&FAMOUS_PEOPLE)
+    }
+}
 
 pub fn random_name() -> String {
-    return fastrand::choice(FAMOUS_PEOPLE).unwrap().to_string();
-}
\ No newline at end of file
+    NameGenerator::default().random()
+}
+
+/// Returned by `random_names` when asked for more names than the pool holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotEnoughNames {
+    pub requested: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for NotEnoughNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "asked for {} unique names but only {} exist",
+            self.requested, self.available
+        )
+    }
+}
+
+impl std::error::Error for NotEnoughNames {}
+
+/// `n` distinct random names, in random order.
+pub fn random_names(n: usize) -> Result<Vec<String>, NotEnoughNames> {
+    if n > FAMOUS_PEOPLE.len() {
+        return Err(NotEnoughNames {
+            requested: n,
+            available: FAMOUS_PEOPLE.len(),
+        });
+    }
+    // the pool has no duplicates, so a shuffled prefix is unique without any retrying
+    let mut pool = FAMOUS_PEOPLE;
+    fastrand::shuffle(&mut pool);
+    Ok(pool[..n].iter().map(|name| name.to_string()).collect())
+}
+
+/// An anonymous "adjective-noun" handle such as "brave-otter".
+pub fn random_handle() -> String {
+    format!(
+        "{}-{}",
+        fastrand::choice(ADJECTIVES).unwrap(),
+        fastrand::choice(NOUNS).unwrap()
+    )
+}
+
+/// Like `random_name`, but the same `seed` always picks the same name.
+pub fn random_name_seeded(seed: u64) -> String {
+    fastrand::Rng::with_seed(seed)
+        .choice(FAMOUS_PEOPLE)
+        .unwrap()
+        .to_string()
+}
+
+/// Number of names `random_name` can pick from.
+pub fn people_count() -> usize {
+    FAMOUS_PEOPLE.len()
+}
+
+/// The name at `index` in the pool, or `None` past the end.
+pub fn person_at(index: usize) -> Option<&'static str> {
+    FAMOUS_PEOPLE.get(index).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_names() {
+        let first = (0..20).map(random_name_seeded).collect::<Vec<_>>();
+        let second = (0..20).map(random_name_seeded).collect::<Vec<_>>();
+        assert_eq!(first, second);
+        assert!(
+            first
+                .iter()
+                .all(|name| FAMOUS_PEOPLE.contains(&name.as_str()))
+        );
+    }
+
+    #[test]
+    fn handles_are_adjective_hyphen_noun() {
+        for _ in 0..50 {
+            let handle = random_handle();
+            let (adjective, noun) = handle.split_once('-').unwrap();
+            assert!(ADJECTIVES.contains(&adjective), "{handle}");
+            assert!(NOUNS.contains(&noun), "{handle}");
+        }
+    }
+
+    #[test]
+    fn random_names_are_distinct() {
+        let names = random_names(people_count()).unwrap();
+        let unique = names.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), people_count());
+    }
+
+    #[test]
+    fn random_names_refuses_more_than_the_pool() {
+        assert_eq!(
+            random_names(people_count() + 1),
+            Err(NotEnoughNames {
+                requested: people_count() + 1,
+                available: people_count(),
+            })
+        );
+    }
+}
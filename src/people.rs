@@ -1,4 +1,4 @@
-pub static FAMOUS_PEOPLE: [&'static str; 325] = [
+pub static FAMOUS_PEOPLE: [&str; 325] = [
     "Albert Einstein",
     "Isaac Newton",
     "Galileo Galilei",
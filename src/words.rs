@@ -0,0 +1,60 @@
+pub static ADJECTIVES: [&str; 50] = [
+    "brave",
+    "calm",
+    "clever",
+    "cosmic",
+    "curious",
+    "daring",
+    "eager",
+    "fancy",
+    "fearless",
+    "fuzzy",
+    "gentle",
+    "giddy",
+    "golden",
+    "happy",
+    "hidden",
+    "humble",
+    "jolly",
+    "keen",
+    "kind",
+    "lively",
+    "lucky",
+    "mellow",
+    "mighty",
+    "misty",
+    "nimble",
+    "noble",
+    "plucky",
+    "proud",
+    "quick",
+    "quiet",
+    "rapid",
+    "rusty",
+    "shiny",
+    "silent",
+    "silly",
+    "sleepy",
+    "sly",
+    "smooth",
+    "snappy",
+    "sunny",
+    "swift",
+    "tidy",
+    "tiny",
+    "vivid",
+    "wandering",
+    "wild",
+    "wise",
+    "witty",
+    "zany",
+    "zesty",
+];
+
+pub static NOUNS: [&str; 50] = [
+    "badger", "bear", "beaver", "bison", "crane", "crow", "deer", "dolphin", "eagle", "falcon",
+    "ferret", "finch", "fox", "gecko", "heron", "ibis", "jaguar", "koala", "lemur", "lynx",
+    "marmot", "moose", "newt", "otter", "owl", "panda", "parrot", "pelican", "penguin", "puffin",
+    "quail", "rabbit", "raccoon", "raven", "robin", "salmon", "seal", "sparrow", "squid", "stoat",
+    "swan", "tiger", "toucan", "turtle", "walrus", "weasel", "whale", "wolf", "wombat", "yak",
+];